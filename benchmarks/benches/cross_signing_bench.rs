@@ -0,0 +1,160 @@
+use criterion::*;
+use matrix_sdk_crypto::{
+    olm::{
+        verify_json_verbose, verify_raw, PickledCrossSigningIdentity, PrivateCrossSigningIdentity,
+    },
+    OlmMachine,
+};
+use ruma::{device_id, user_id, DeviceId, UserId};
+use serde_json::json;
+use tokio::runtime::{Builder, Runtime};
+
+fn alice_id() -> &'static UserId {
+    user_id!("@alice:example.org")
+}
+
+fn alice_device_id() -> &'static DeviceId {
+    device_id!("JLAFKJWSCS")
+}
+
+fn device_keys_message() -> String {
+    json!({
+        "algorithms": ["m.olm.v1.curve25519-aes-sha2", "m.megolm.v1.aes-sha2"],
+        "device_id": alice_device_id(),
+        "keys": {
+            "curve25519:JLAFKJWSCS": "3C5BFWi2Y8MaVvjM8M22DBmh24PmgR0nPvJOIArzgyI",
+            "ed25519:JLAFKJWSCS": "lEuiRJBit0IG6nUf5pUzWTUEsRVVe/HJkoKuEww9ULI"
+        },
+        "user_id": alice_id(),
+    })
+    .to_string()
+}
+
+/// Bootstrap cross signing for a new machine and get its private cross signing
+/// identity.
+fn cross_signing_identity(runtime: &Runtime) -> PrivateCrossSigningIdentity {
+    let machine = runtime.block_on(OlmMachine::new(alice_id(), alice_device_id()));
+    runtime.block_on(machine.bootstrap_cross_signing(false)).unwrap();
+
+    let export = runtime
+        .block_on(machine.export_cross_signing_keys())
+        .expect("The cross signing keys should be exportable");
+    let identity = PrivateCrossSigningIdentity::empty(alice_id());
+    runtime
+        .block_on(identity.import_secrets_unchecked(
+            export.master_key.as_deref(),
+            export.self_signing_key.as_deref(),
+            export.user_signing_key.as_deref(),
+        ))
+        .unwrap();
+
+    identity
+}
+
+pub fn cross_signing_bootstrap(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+    let machine = runtime.block_on(OlmMachine::new(alice_id(), alice_device_id()));
+
+    let mut group = c.benchmark_group("Cross signing bootstrap");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function(BenchmarkId::new("memory store", "new identity"), |b| {
+        b.to_async(&runtime).iter(|| async { machine.bootstrap_cross_signing(true).await.unwrap() })
+    });
+
+    group.finish()
+}
+
+pub fn cross_signing_sign(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+    let machine = runtime.block_on(OlmMachine::new(alice_id(), alice_device_id()));
+    runtime.block_on(machine.bootstrap_cross_signing(false)).unwrap();
+
+    let message = device_keys_message();
+
+    let mut group = c.benchmark_group("Cross signing signing");
+    group.throughput(Throughput::Bytes(message.len() as u64));
+
+    group.bench_with_input(
+        BenchmarkId::new("memory store", "device keys"),
+        &message,
+        |b, message| b.to_async(&runtime).iter(|| async { machine.sign(message).await }),
+    );
+
+    group.finish()
+}
+
+pub fn cross_signing_verify(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+    let identity = cross_signing_identity(&runtime);
+
+    let message = device_keys_message();
+    let json: serde_json::Value = serde_json::from_str(&message).unwrap();
+    let signature = runtime.block_on(identity.sign_json_str(&message)).unwrap();
+    let public_key = runtime
+        .block_on(identity.master_public_key())
+        .and_then(|k| k.get_first_key())
+        .expect("The identity should have a master key");
+    let key_id = format!("ed25519:{}", public_key.to_base64());
+
+    let mut group = c.benchmark_group("Cross signing verification");
+    group.throughput(Throughput::Bytes(message.len() as u64));
+
+    group.bench_with_input(
+        BenchmarkId::new("verify_json_verbose", "device keys"),
+        &json,
+        |b, json| b.iter(|| verify_json_verbose(&public_key, json, &signature).unwrap()),
+    );
+
+    let (public_key, signature) = (public_key.to_base64(), signature.to_base64());
+
+    group.bench_with_input(BenchmarkId::new("verify_raw", "device keys"), &json, |b, json| {
+        b.iter(|| verify_raw(&key_id, &public_key, json, &signature).unwrap())
+    });
+
+    group.finish()
+}
+
+pub fn cross_signing_pickling(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+    let identity = cross_signing_identity(&runtime);
+
+    let pickle = serde_json::to_value(runtime.block_on(identity.pickle()).unwrap()).unwrap();
+
+    let mut group = c.benchmark_group("Cross signing pickling");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function(BenchmarkId::new("pickle", "identity"), |b| {
+        b.to_async(&runtime).iter(|| async { identity.pickle().await.unwrap() })
+    });
+
+    group.bench_with_input(BenchmarkId::new("from_pickle", "identity"), &pickle, |b, pickle| {
+        b.to_async(&runtime).iter_batched(
+            || -> PickledCrossSigningIdentity { serde_json::from_value(pickle.clone()).unwrap() },
+            |pickle| async move { PrivateCrossSigningIdentity::from_pickle(pickle).await.unwrap() },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish()
+}
+
+fn criterion() -> Criterion {
+    #[cfg(target_os = "linux")]
+    let criterion = Criterion::default().with_profiler(pprof::criterion::PProfProfiler::new(
+        100,
+        pprof::criterion::Output::Flamegraph(None),
+    ));
+    #[cfg(not(target_os = "linux"))]
+    let criterion = Criterion::default();
+
+    criterion
+}
+
+criterion_group! {
+    name = benches;
+    config = criterion();
+    targets = cross_signing_bootstrap, cross_signing_sign, cross_signing_verify,
+        cross_signing_pickling,
+}
+criterion_main!(benches);
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use ruma::{IdParseError, OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use std::sync::Arc;
+
+use ruma::{serde::CanonicalJsonError, IdParseError, OwnedDeviceId, OwnedRoomId, OwnedUserId};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
@@ -182,6 +184,65 @@ pub enum SignatureError {
     /// The signed object couldn't be deserialized.
     #[error(transparent)]
     JsonError(#[from] SerdeError),
+
+    /// The base64 encoded public key has an unexpected length.
+    #[error("the encoded public key has an invalid length, expected {expected}, got {got}")]
+    InvalidPublicKeyLength {
+        /// The length an encoded key of the given algorithm should have.
+        expected: usize,
+        /// The length the encoded key actually had.
+        got: usize,
+    },
+
+    /// The decoded signature doesn't have the length of an Ed25519 signature.
+    #[error("the decoded signature has an invalid length, expected 64, got {got}")]
+    InvalidSignatureLength {
+        /// The length the decoded signature actually had.
+        got: usize,
+    },
+
+    /// The JSON object contains an integer outside of the range canonical JSON
+    /// supports.
+    #[error("the JSON object contains an integer outside of the canonical JSON range")]
+    IntegerOutOfRange,
+
+    /// The canonical JSON form of the object that should be signed doesn't
+    /// parse back into the same object.
+    #[error("the canonical JSON form of the object doesn't round trip")]
+    NonRoundTrippable,
+
+    /// The JSON value can't be represented as canonical JSON, e.g. because it
+    /// contains a floating point number.
+    #[error(transparent)]
+    CanonicalJson(#[from] CanonicalJsonError),
+
+    /// The JSON object that should be signed contains duplicate keys.
+    #[error("the JSON object contains duplicate keys")]
+    DuplicateKey,
+
+    /// The signed content belongs to a different user than the one that
+    /// signed it with a self signing key.
+    #[error("the signed content doesn't belong to the owner of the self signing key")]
+    SignerContentMismatch,
+
+    /// Two of the cross signing keys of an identity share the same public
+    /// key.
+    #[error("two of the cross signing keys share the same public key")]
+    DuplicatePublicKey,
+
+    /// The signed verification message doesn't contain a transaction id.
+    #[error("the signed verification message doesn't contain a transaction id")]
+    MissingTransactionId,
+
+    /// The signature over the canonical JSON form of an object couldn't be
+    /// verified.
+    #[error("the signature over the canonical JSON couldn't be verified: {source}")]
+    CanonicalJsonVerificationError {
+        /// The canonical JSON form the signature was checked against.
+        canonical_json: Arc<str>,
+        /// The reason the signature didn't verify.
+        source: vodozemac::SignatureError,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -48,6 +48,7 @@ use crate::{
     olm::{InboundGroupSession, Session, VerifyJson},
     store::{Changes, CryptoStore, DeviceChanges, Result as StoreResult},
     types::{DeviceKey, DeviceKeys, SignedKey},
+    utilities::fingerprint,
     verification::VerificationMachine,
     OutgoingVerificationRequest, ReadOnlyAccount, Sas, ToDeviceRequest, VerificationRequest,
 };
@@ -416,6 +417,14 @@ impl ReadOnlyDevice {
         })
     }
 
+    /// Get the Ed25519 key of the given device formatted as a fingerprint.
+    ///
+    /// The fingerprint is the key in uppercase hex, grouped into blocks of
+    /// four characters separated by a space, meant to be compared by users.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.ed25519_key().map(|k| fingerprint(&k))
+    }
+
     /// Get the Ed25519 key of the given device.
     pub fn ed25519_key(&self) -> Option<Ed25519PublicKey> {
         self.get_key(DeviceKeyAlgorithm::Ed25519).and_then(|k| {
@@ -712,6 +721,10 @@ pub(crate) mod tests {
             device.ed25519_key().unwrap(),
             Ed25519PublicKey::from_base64("2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4").unwrap(),
         );
+        assert_eq!(
+            device.fingerprint().unwrap(),
+            "DBFE 4B58 9328 C39C E126 A6A4 57CF 1221 CEEA FF5A 5AF1 F9A4 7E00 33C7 BDB0 F46E"
+        );
     }
 
     #[test]
@@ -38,9 +38,10 @@ use vodozemac::Ed25519PublicKey;
 use super::{atomic_bool_deserializer, atomic_bool_serializer};
 use crate::{
     error::SignatureError,
-    olm::VerifyJson,
+    olm::{verify_self_signatures, VerifyJson},
     store::{Changes, IdentityChanges},
     types::{CrossSigningKey, DeviceKeys, SigningKey},
+    utilities::fingerprint,
     verification::VerificationMachine,
     CryptoStoreError, OutgoingVerificationRequest, ReadOnlyDevice, VerificationRequest,
 };
@@ -398,6 +399,15 @@ impl MasterPubkey {
         &self.0.keys
     }
 
+    /// Get the id of the first available key.
+    ///
+    /// The id can be used directly as the key of a signatures map. There's
+    /// usually only a single key so this will usually be the id of the only
+    /// key.
+    pub fn as_device_key_id(&self) -> Option<OwnedDeviceKeyId> {
+        self.0.keys.keys().next().cloned()
+    }
+
     /// Get the list of `KeyUsage` that is set for this key.
     pub fn usage(&self) -> &[KeyUsage] {
         &self.0.usage
@@ -429,6 +439,14 @@ impl MasterPubkey {
         }
     }
 
+    /// Get the first available master key formatted as a fingerprint.
+    ///
+    /// The fingerprint is the key in uppercase hex, grouped into blocks of
+    /// four characters separated by a space, meant to be compared by users.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.get_first_key().map(|k| fingerprint(&k))
+    }
+
     /// Check if the given cross signing sub-key is signed by the master key.
     ///
     /// # Arguments
@@ -488,6 +506,15 @@ impl UserSigningPubkey {
         &self.0.keys
     }
 
+    /// Get the id of the first available key.
+    ///
+    /// The id can be used directly as the key of a signatures map. There's
+    /// usually only a single key so this will usually be the id of the only
+    /// key.
+    pub fn as_device_key_id(&self) -> Option<OwnedDeviceKeyId> {
+        self.0.keys.keys().next().cloned()
+    }
+
     /// Check if the given master key is signed by this user signing key.
     ///
     /// # Arguments
@@ -537,10 +564,23 @@ impl SelfSigningPubkey {
         &self.0.keys
     }
 
+    /// Get the id of the first available key.
+    ///
+    /// The id can be used directly as the key of a signatures map. There's
+    /// usually only a single key so this will usually be the id of the only
+    /// key.
+    pub fn as_device_key_id(&self) -> Option<OwnedDeviceKeyId> {
+        self.0.keys.keys().next().cloned()
+    }
+
     pub(crate) fn verify_device_keys(&self, device_keys: DeviceKeys) -> Result<(), SignatureError> {
         let (key_id, key) = self.0.keys.iter().next().ok_or(SignatureError::MissingSigningKey)?;
         // TODO check that the usage is OK.
 
+        if device_keys.user_id != self.0.user_id {
+            return Err(SignatureError::SignerContentMismatch);
+        }
+
         let mut device = to_value(device_keys)?;
 
         if let SigningKey::Ed25519(key) = key {
@@ -681,16 +721,29 @@ impl ReadOnlyUserIdentity {
     /// * `self signing key` - The self signing key of user identity.
     ///
     /// Returns a `SignatureError` if the self signing key fails to be correctly
-    /// verified by the given master key.
+    /// verified by the given master key or if one of the keys carries an
+    /// invalid signature of itself.
     pub(crate) fn new(
         master_key: MasterPubkey,
         self_signing_key: SelfSigningPubkey,
     ) -> Result<Self, SignatureError> {
-        master_key.verify_subkey(&self_signing_key)?;
+        Self::check_keys(&master_key, &self_signing_key)?;
 
         Ok(Self { user_id: (&*master_key.0.user_id).into(), master_key, self_signing_key })
     }
 
+    /// Check that the self signing key is signed by the master key and that
+    /// the self signatures of both keys are valid.
+    fn check_keys(
+        master_key: &MasterPubkey,
+        self_signing_key: &SelfSigningPubkey,
+    ) -> Result<(), SignatureError> {
+        verify_self_signatures(master_key.as_ref())?;
+        verify_self_signatures(self_signing_key.as_ref())?;
+
+        master_key.verify_subkey(self_signing_key)
+    }
+
     #[cfg(test)]
     pub(crate) async fn from_private(identity: &crate::olm::PrivateCrossSigningIdentity) -> Self {
         let master_key = identity.master_key.lock().await.as_ref().unwrap().public_key.clone();
@@ -723,13 +776,15 @@ impl ReadOnlyUserIdentity {
     ///
     /// * `self_signing_key` - The new self signing key of user identity.
     ///
-    /// Returns a `SignatureError` if we failed to update the identity.
+    /// Returns a `SignatureError` if the self signing key fails to be correctly
+    /// verified by the given master key or if one of the keys carries an
+    /// invalid signature of itself.
     pub(crate) fn update(
         &mut self,
         master_key: MasterPubkey,
         self_signing_key: SelfSigningPubkey,
     ) -> Result<(), SignatureError> {
-        master_key.verify_subkey(&self_signing_key)?;
+        Self::check_keys(&master_key, &self_signing_key)?;
 
         self.master_key = master_key;
         self.self_signing_key = self_signing_key;
@@ -790,15 +845,15 @@ impl ReadOnlyOwnUserIdentity {
     ///
     /// * `user_signing_key` - The user signing key of user identity.
     ///
-    /// Returns a `SignatureError` if the self signing key fails to be correctly
-    /// verified by the given master key.
+    /// Returns a `SignatureError` if the subkeys fail to be correctly verified
+    /// by the given master key, if one of the keys carries an invalid signature
+    /// of itself or if two of the keys share the same public key.
     pub(crate) fn new(
         master_key: MasterPubkey,
         self_signing_key: SelfSigningPubkey,
         user_signing_key: UserSigningPubkey,
     ) -> Result<Self, SignatureError> {
-        master_key.verify_subkey(&self_signing_key)?;
-        master_key.verify_subkey(&user_signing_key)?;
+        Self::check_keys(&master_key, &self_signing_key, &user_signing_key)?;
 
         Ok(Self {
             user_id: (&*master_key.0.user_id).into(),
@@ -809,6 +864,37 @@ impl ReadOnlyOwnUserIdentity {
         })
     }
 
+    /// Check that the subkeys are signed by the master key, that the self
+    /// signatures of all the keys are valid and that no two of the keys share
+    /// the same public key.
+    fn check_keys(
+        master_key: &MasterPubkey,
+        self_signing_key: &SelfSigningPubkey,
+        user_signing_key: &UserSigningPubkey,
+    ) -> Result<(), SignatureError> {
+        verify_self_signatures(master_key.as_ref())?;
+        verify_self_signatures(self_signing_key.as_ref())?;
+        verify_self_signatures(user_signing_key.as_ref())?;
+
+        let mut public_keys = Vec::new();
+
+        for key in master_key
+            .keys()
+            .values()
+            .chain(self_signing_key.keys().values())
+            .chain(user_signing_key.keys().values())
+        {
+            if public_keys.contains(&key) {
+                return Err(SignatureError::DuplicatePublicKey);
+            }
+
+            public_keys.push(key);
+        }
+
+        master_key.verify_subkey(self_signing_key)?;
+        master_key.verify_subkey(user_signing_key)
+    }
+
     /// Get the user id of this identity.
     pub fn user_id(&self) -> &UserId {
         &self.user_id
@@ -887,15 +973,16 @@ impl ReadOnlyOwnUserIdentity {
     ///
     /// * `user_signing_key` - The new user signing key of user identity.
     ///
-    /// Returns a `SignatureError` if we failed to update the identity.
+    /// Returns a `SignatureError` if the subkeys fail to be correctly verified
+    /// by the given master key, if one of the keys carries an invalid signature
+    /// of itself or if two of the keys share the same public key.
     pub(crate) fn update(
         &mut self,
         master_key: MasterPubkey,
         self_signing_key: SelfSigningPubkey,
         user_signing_key: UserSigningPubkey,
     ) -> Result<(), SignatureError> {
-        master_key.verify_subkey(&self_signing_key)?;
-        master_key.verify_subkey(&user_signing_key)?;
+        Self::check_keys(&master_key, &self_signing_key, &user_signing_key)?;
 
         self.self_signing_key = self_signing_key;
         self.user_signing_key = user_signing_key;
@@ -987,6 +1074,7 @@ pub(crate) mod tests {
         store::MemoryStore,
         types::CrossSigningKey,
         verification::VerificationMachine,
+        SignatureError,
     };
 
     #[test]
@@ -1005,6 +1093,44 @@ pub(crate) mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn own_identity_with_foreign_subkeys() {
+        let user_id = user_id!("@example:localhost");
+        let other_user_id = user_id!("@other:localhost");
+        let response = own_key_query();
+
+        let master_key: CrossSigningKey =
+            response.master_keys.get(user_id).unwrap().deserialize_as().unwrap();
+        let user_signing: CrossSigningKey =
+            response.user_signing_keys.get(user_id).unwrap().deserialize_as().unwrap();
+        let self_signing: CrossSigningKey =
+            response.self_signing_keys.get(user_id).unwrap().deserialize_as().unwrap();
+
+        let mut foreign_self_signing = self_signing.clone();
+        foreign_self_signing.user_id = other_user_id.to_owned();
+
+        assert!(matches!(
+            ReadOnlyOwnUserIdentity::new(
+                master_key.clone().into(),
+                foreign_self_signing.into(),
+                user_signing.clone().into()
+            ),
+            Err(SignatureError::UserIdMismatch)
+        ));
+
+        let mut foreign_user_signing = user_signing;
+        foreign_user_signing.user_id = other_user_id.to_owned();
+
+        assert!(matches!(
+            ReadOnlyOwnUserIdentity::new(
+                master_key.into(),
+                self_signing.into(),
+                foreign_user_signing.into()
+            ),
+            Err(SignatureError::UserIdMismatch)
+        ));
+    }
+
     #[test]
     fn other_identity_create() {
         get_other_identity();
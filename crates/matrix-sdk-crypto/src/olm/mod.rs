@@ -31,8 +31,21 @@ pub use group_sessions::{
     PickledInboundGroupSession, PickledOutboundGroupSession, SessionKey, ShareInfo,
 };
 pub use session::{PickledSession, Session};
-pub use signing::{CrossSigningStatus, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
+#[cfg(feature = "legacy-compat")]
+pub use signing::verify_json_legacy_unsigned;
+pub use signing::{
+    ed25519_key_id, parse_authorized_key_line, prune_signatures, remap_signature_key_id,
+    signature_key_ids, signatures_to_upload, signers_are_all_deleted, signing_self_test,
+    signing_self_test_with, verify_against_any, verify_detached, verify_json_verbose,
+    verify_json_with_canonicalizer, verify_raw, verify_self_signatures,
+    verify_verification_message, verify_with_bytes, CrossSigningStatus,
+    PickledCrossSigningIdentity, PrivateCrossSigningIdentity, PublicIdentity, ResignReport,
+    SelfTestError, VerifiedMessage,
+};
+#[cfg(feature = "metrics")]
+pub use utility::VerificationStats;
 pub(crate) use utility::VerifyJson;
+pub use utility::{Canonicalizer, RumaCanonicalizer};
 pub use vodozemac::olm::IdentityKeys;
 
 #[cfg(test)]
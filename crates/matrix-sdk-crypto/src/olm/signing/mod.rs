@@ -19,8 +19,21 @@ use std::sync::{
     Arc,
 };
 
+use futures_util::future::join3;
 use matrix_sdk_common::locks::Mutex;
-use pk_signing::{MasterSigning, PickledSignings, SelfSigning, Signing, SigningError, UserSigning};
+#[cfg(feature = "legacy-compat")]
+pub use pk_signing::verify_json_legacy_unsigned;
+pub use pk_signing::{
+    ed25519_key_id, parse_authorized_key_line, prune_signatures, remap_signature_key_id,
+    signature_key_ids, signatures_to_upload, signers_are_all_deleted, signing_self_test,
+    signing_self_test_with, verify_against_any, verify_detached, verify_json_verbose,
+    verify_json_with_canonicalizer, verify_raw, verify_self_signatures,
+    verify_verification_message, verify_with_bytes, PublicIdentity, SelfTestError, VerifiedMessage,
+};
+use pk_signing::{
+    MasterSigning, PickledSignings, SelfSigning, Signing, SigningError, UserSigning,
+    PICKLED_SIGNINGS_FORMAT_VERSION,
+};
 use ruma::{
     api::client::keys::upload_signatures::v3::{Request as SignatureUploadRequest, SignedKeys},
     encryption::KeyUsage,
@@ -28,15 +41,16 @@ use ruma::{
     OwnedUserId, UserId,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Error as JsonError;
+use serde_json::{Error as JsonError, Value};
 use vodozemac::Ed25519Signature;
 
 use crate::{
     error::SignatureError,
     identities::{MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
+    olm::Canonicalizer,
     requests::UploadSigningKeysRequest,
     store::SecretImportError,
-    types::DeviceKeys,
+    types::{CrossSigningKey, DeviceKeys},
     OwnUserIdentity, ReadOnlyAccount, ReadOnlyDevice, ReadOnlyOwnUserIdentity,
     ReadOnlyUserIdentity,
 };
@@ -107,6 +121,26 @@ pub struct CrossSigningStatus {
     pub has_user_signing: bool,
 }
 
+/// The outcome of re-signing keys using
+/// [`PrivateCrossSigningIdentity::resign_all()`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResignReport {
+    /// The number of keys whose signatures changed.
+    pub changed: usize,
+    /// The number of keys that were re-signed but kept the same signatures.
+    pub unchanged: usize,
+}
+
+impl ResignReport {
+    fn record(&mut self, changed: bool) {
+        if changed {
+            self.changed += 1;
+        } else {
+            self.unchanged += 1;
+        }
+    }
+}
+
 impl PrivateCrossSigningIdentity {
     /// Get the user id that this identity belongs to.
     pub fn user_id(&self) -> &UserId {
@@ -160,6 +194,22 @@ impl PrivateCrossSigningIdentity {
         self.master_key.lock().await.as_ref().map(|m| m.public_key.to_owned())
     }
 
+    /// Get the shareable public identity of our master key, if we have one.
+    ///
+    /// The identity contains no secret material and can be shared out of band,
+    /// e.g. to publish the fingerprint of the master key.
+    pub async fn public_identity(&self) -> Option<PublicIdentity> {
+        self.master_key.lock().await.as_ref().map(|m| m.public_identity())
+    }
+
+    /// Get the public part of our master key as a line of an SSH
+    /// `authorized_keys` file, if we have a master key.
+    ///
+    /// The line can be parsed back using [`parse_authorized_key_line`].
+    pub async fn master_key_authorized_key_line(&self, comment: &str) -> Option<String> {
+        self.master_key.lock().await.as_ref().map(|m| m.to_authorized_key_line(comment))
+    }
+
     /// Get the public part of the self-signing key, if we have one.
     pub async fn self_signing_public_key(&self) -> Option<SelfSigningPubkey> {
         self.self_signing_key.lock().await.as_ref().map(|k| k.public_key.to_owned())
@@ -181,7 +231,7 @@ impl PrivateCrossSigningIdentity {
     pub async fn export_secret(&self, secret_name: &SecretName) -> Option<String> {
         match secret_name {
             SecretName::CrossSigningMasterKey => {
-                self.master_key.lock().await.as_ref().map(|m| m.export_seed())
+                self.master_key.lock().await.as_ref().map(|m| m.to_secret_payload())
             }
             SecretName::CrossSigningUserSigningKey => {
                 self.user_signing_key.lock().await.as_ref().map(|m| m.export_seed())
@@ -217,16 +267,15 @@ impl PrivateCrossSigningIdentity {
         user_signing_key: Option<&str>,
     ) -> Result<(), SecretImportError> {
         let master = if let Some(master_key) = master_key {
-            let master = MasterSigning::from_base64(self.user_id().to_owned(), master_key)?;
-
-            if public_identity.master_key() == &master.public_key {
-                Ok(Some(master))
-            } else {
-                Err(SecretImportError::MismatchedPublicKeys)
-            }
+            let user_id = self.user_id().to_owned();
+            Some(MasterSigning::from_secret_payload(
+                user_id,
+                master_key,
+                public_identity.master_key(),
+            )?)
         } else {
-            Ok(None)
-        }?;
+            None
+        };
 
         let user_signing = if let Some(user_signing_key) = user_signing_key {
             let subkey = UserSigning::from_base64(self.user_id().to_owned(), user_signing_key)?;
@@ -481,6 +530,63 @@ impl PrivateCrossSigningIdentity {
         Ok(SignatureUploadRequest::new(signed_keys))
     }
 
+    /// Recompute and replace all the signatures this identity is responsible
+    /// for.
+    ///
+    /// Our own devices are signed with the self signing key, our own self
+    /// signing and user signing keys with the master key, and the master keys
+    /// of other users with the user signing key. Devices and keys we don't
+    /// have the needed private key for are left untouched.
+    ///
+    /// This is meant to be used as a migration after the way signatures are
+    /// computed changed, e.g. after a change of the canonical JSON rules.
+    pub async fn resign_all(
+        &self,
+        devices: &mut [DeviceKeys],
+        subkeys: &mut [CrossSigningKey],
+    ) -> Result<ResignReport, SignatureError> {
+        let mut report = ResignReport::default();
+
+        if let Some(self_signing) = self.self_signing_key.lock().await.as_ref() {
+            for device_keys in devices.iter_mut().filter(|d| &*d.user_id == self.user_id()) {
+                let previous = device_keys.signatures.get(self.user_id()).cloned();
+                self_signing.sign_device(device_keys)?;
+
+                report.record(previous.as_ref() != device_keys.signatures.get(self.user_id()));
+            }
+        }
+
+        let is_own = |k: &CrossSigningKey| &*k.user_id == self.user_id();
+        let is_master_key = |k: &CrossSigningKey| k.usage.contains(&KeyUsage::Master);
+
+        if let Some(master) = self.master_key.lock().await.as_ref() {
+            for subkey in subkeys.iter_mut().filter(|k| is_own(k) && !is_master_key(k)) {
+                let previous = subkey.signatures.get(self.user_id()).cloned();
+                master.sign_subkey(subkey);
+
+                report.record(previous.as_ref() != subkey.signatures.get(self.user_id()));
+            }
+        }
+
+        if let Some(user_signing) = self.user_signing_key.lock().await.as_ref() {
+            for master_key in subkeys.iter_mut().filter(|k| !is_own(k) && is_master_key(k)) {
+                let previous = master_key.signatures.get(self.user_id()).cloned();
+                let signature =
+                    user_signing.inner.sign_json(serde_json::to_value(&*master_key)?)?;
+
+                master_key
+                    .signatures
+                    .entry(self.user_id().to_owned())
+                    .or_default()
+                    .insert(user_signing.inner.key_id(), signature.to_base64());
+
+                report.record(previous.as_ref() != master_key.signatures.get(self.user_id()));
+            }
+        }
+
+        Ok(report)
+    }
+
     pub(crate) async fn sign(&self, message: &str) -> Result<Ed25519Signature, SignatureError> {
         Ok(self
             .master_key
@@ -491,6 +597,54 @@ impl PrivateCrossSigningIdentity {
             .sign(message))
     }
 
+    /// Sign the `auth_data` of a `m.megolm_backup.v1.curve25519-aes-sha2` key
+    /// backup with our master key.
+    ///
+    /// The signature is added to the `signatures` of the `auth_data`, next to
+    /// the signature of our device it usually already carries.
+    pub async fn sign_backup_auth_data(&self, auth_data: &mut Value) -> Result<(), SignatureError> {
+        self.master_key
+            .lock()
+            .await
+            .as_ref()
+            .ok_or(SignatureError::MissingSigningKey)?
+            .sign_backup_auth_data(auth_data)
+    }
+
+    /// Sign the given JSON string with our master key.
+    ///
+    /// The canonical JSON form of the string gets signed, objects that contain
+    /// a key multiple times are rejected since other implementations might
+    /// interpret them differently.
+    pub async fn sign_json_str(&self, json: &str) -> Result<Ed25519Signature, SignatureError> {
+        self.master_key
+            .lock()
+            .await
+            .as_ref()
+            .ok_or(SignatureError::MissingSigningKey)?
+            .inner
+            .sign_json_str(json)
+    }
+
+    /// Sign the given JSON object with our master key, canonicalizing it with
+    /// the given canonicalizer.
+    ///
+    /// The signature can be checked using
+    /// [`verify_json_with_canonicalizer`] and the same canonicalizer.
+    pub async fn sign_json_with(
+        &self,
+        json: Value,
+        canonicalizer: &impl Canonicalizer,
+    ) -> Result<Ed25519Signature, SignatureError> {
+        self.master_key
+            .lock()
+            .await
+            .as_ref()
+            .ok_or(SignatureError::MissingSigningKey)?
+            .inner
+            .sign_json_with(json, canonicalizer)
+    }
+
     /// Create a new identity for the given Olm Account.
     ///
     /// Returns the new identity, the upload signing keys request and a
@@ -528,14 +682,45 @@ impl PrivateCrossSigningIdentity {
         (identity, request, signature_request)
     }
 
+    /// Create a new cross signing identity using the given raw bytes of an
+    /// Ed25519 seed as the master key and subkeys derived from it.
+    ///
+    /// This creates a new identity, it can't be used to restore an existing
+    /// one. The self signing and user signing keys are derived from the master
+    /// key, while the subkeys of existing identities, including the ones this
+    /// crate creates otherwise, are random. Only identities that were created
+    /// using this constructor can be recreated from their master seed.
+    ///
+    /// Returns an error if the seed isn't exactly 32 bytes long.
+    pub fn new_with_derived_subkeys(
+        user_id: OwnedUserId,
+        seed: Vec<u8>,
+    ) -> Result<Self, SigningError> {
+        let master = Signing::from_seed(seed)?;
+        let self_signing = master.derive_subkey(KeyUsage::SelfSigning)?;
+        let user_signing = master.derive_subkey(KeyUsage::UserSigning)?;
+
+        let public_key = master.cross_signing_key(user_id.clone(), KeyUsage::Master);
+        let master = MasterSigning { inner: master, public_key: public_key.into() };
+
+        Ok(Self::with_subkeys(&user_id, master, self_signing, user_signing))
+    }
+
     async fn new_helper(user_id: &UserId, master: MasterSigning) -> Self {
-        let user = Signing::new();
+        Self::with_subkeys(user_id, master, Signing::new(), Signing::new())
+    }
+
+    fn with_subkeys(
+        user_id: &UserId,
+        master: MasterSigning,
+        self_signing: Signing,
+        user: Signing,
+    ) -> Self {
         let mut public_key = user.cross_signing_key(user_id.to_owned(), KeyUsage::UserSigning);
         master.sign_subkey(&mut public_key);
 
         let user = UserSigning { inner: user, public_key: public_key.into() };
 
-        let self_signing = Signing::new();
         let mut public_key =
             self_signing.cross_signing_key(user_id.to_owned(), KeyUsage::SelfSigning);
         master.sign_subkey(&mut public_key);
@@ -588,22 +773,20 @@ impl PrivateCrossSigningIdentity {
 
     /// Store the cross signing identity as a pickle.
     ///
-    /// # Arguments
-    ///
-    /// * `pickle_key` - The key that should be used to encrypt the signing
-    /// object, must be 32 bytes long.
-    ///
-    /// # Panics
-    ///
-    /// This will panic if the provided pickle key isn't 32 bytes long.
+    /// The signing keys are pickled as they are, the pickle isn't encrypted.
     pub async fn pickle(&self) -> Result<PickledCrossSigningIdentity, JsonError> {
-        let master_key = self.master_key.lock().await.as_ref().map(|m| m.pickle());
-
-        let self_signing_key = self.self_signing_key.lock().await.as_ref().map(|m| m.pickle());
-
-        let user_signing_key = self.user_signing_key.lock().await.as_ref().map(|m| m.pickle());
-
-        let keys = PickledSignings { master_key, user_signing_key, self_signing_key };
+        let (master_key, self_signing_key, user_signing_key) = join3(
+            self.master_key.lock(),
+            self.self_signing_key.lock(),
+            self.user_signing_key.lock(),
+        )
+        .await;
+
+        let keys = PickledSignings::from_signings(
+            master_key.as_ref(),
+            user_signing_key.as_ref(),
+            self_signing_key.as_ref(),
+        );
 
         Ok(PickledCrossSigningIdentity {
             user_id: self.user_id.as_ref().to_owned(),
@@ -613,13 +796,13 @@ impl PrivateCrossSigningIdentity {
     }
 
     /// Restore the private cross signing identity from a pickle.
-    ///
-    /// # Panic
-    ///
-    /// Panics if the pickle_key isn't 32 bytes long.
     pub async fn from_pickle(pickle: PickledCrossSigningIdentity) -> Result<Self, SigningError> {
         let keys = pickle.keys;
 
+        if keys.format_version != PICKLED_SIGNINGS_FORMAT_VERSION {
+            return Err(SigningError::UnsupportedPickleVersion(keys.format_version));
+        }
+
         let master = keys.master_key.map(MasterSigning::from_pickle).transpose()?;
         let self_signing = keys.self_signing_key.map(SelfSigning::from_pickle).transpose()?;
         let user_signing = keys.user_signing_key.map(UserSigning::from_pickle).transpose()?;
@@ -651,13 +834,36 @@ impl PrivateCrossSigningIdentity {
 
 #[cfg(test)]
 mod tests {
-    use matrix_sdk_test::async_test;
-    use ruma::{device_id, user_id, UserId};
+    use std::collections::{BTreeMap, BTreeSet};
 
-    use super::{PrivateCrossSigningIdentity, Signing};
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        device_id, encryption::KeyUsage, serde::CanonicalJsonValue, user_id, DeviceKeyAlgorithm,
+        DeviceKeyId, UserId,
+    };
+    use serde_json::{json, Value};
+
+    use super::{
+        pk_signing::{
+            ed25519_key_id, parse_authorized_key_line, prune_signatures, remap_signature_key_id,
+            signature_key_ids, signatures_to_upload, signers_are_all_deleted, signing_self_test,
+            signing_self_test_with, usage_serializes_to_spec_name, verify_against_any,
+            verify_detached, verify_json_verbose, verify_json_with_canonicalizer, verify_raw,
+            verify_self_signatures, verify_verification_message, verify_with_bytes, MasterSigning,
+            PublicIdentity, SelfTestError, VerifiedMessage,
+        },
+        PrivateCrossSigningIdentity, ResignReport, Signing, SigningError,
+        PICKLED_SIGNINGS_FORMAT_VERSION,
+    };
     use crate::{
-        identities::{ReadOnlyDevice, ReadOnlyUserIdentity},
-        olm::ReadOnlyAccount,
+        identities::{
+            ReadOnlyDevice, ReadOnlyOwnUserIdentity, ReadOnlyUserIdentity, SelfSigningPubkey,
+            UserSigningPubkey,
+        },
+        olm::{Canonicalizer, ReadOnlyAccount, RumaCanonicalizer},
+        store::SecretImportError,
+        types::{CrossSigningKey, SigningKey},
+        SignatureError,
     };
 
     fn user_id() -> &'static UserId {
@@ -674,100 +880,1572 @@ mod tests {
         assert!(signing.verify(message, &signature).is_ok());
     }
 
+    #[async_test]
+    async fn sign_json_ignores_unsigned() {
+        use crate::olm::VerifyJson;
+
+        let signing = Signing::new();
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let device_keys = ReadOnlyDevice::from_account(&account).await.as_device_keys().to_owned();
+
+        let mut without_unsigned = serde_json::to_value(&device_keys).unwrap();
+        without_unsigned.as_object_mut().unwrap().remove("unsigned");
+
+        let mut with_unsigned = without_unsigned.clone();
+        with_unsigned["unsigned"] = json!({ "device_display_name": "Alice's phone" });
+
+        let signature = signing.sign_json(with_unsigned.clone()).unwrap();
+        assert_eq!(signature.to_base64(), signing.sign_json(without_unsigned).unwrap().to_base64());
+
+        with_unsigned["signatures"] = json!({
+            user_id().as_str(): { signing.key_id().as_str(): signature.to_base64() }
+        });
+        signing.public_key().verify_json(user_id(), &signing.key_id(), &mut with_unsigned).unwrap();
+    }
+
     #[test]
-    fn pickling_signing() {
+    fn sign_and_verify_json() {
         let signing = Signing::new();
-        let pickled = signing.pickle();
+        let mut json = json!({
+            "user_id": "@example:localhost",
+            "keys": { "ed25519:DEVICEID": "key" },
+            "unsigned": { "age": 1 },
+        });
+
+        let signature = signing.sign_json(json.clone()).unwrap();
+        json["signatures"] = json!({
+            user_id().as_str(): { signing.key_id().as_str(): signature.to_base64() }
+        });
+
+        signing.verify_json(&json, &signature).unwrap();
+        assert!(Signing::new().verify_json(&json, &signature).is_err());
+
+        json["user_id"] = "@other:localhost".into();
+        assert!(matches!(
+            signing.verify_json(&json, &signature),
+            Err(SignatureError::VerificationError(_))
+        ));
+    }
 
-        let unpickled = Signing::from_pickle(pickled).unwrap();
+    #[test]
+    fn signing_key_from_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
 
-        assert_eq!(signing.public_key(), unpickled.public_key());
+        let signing = Signing::new_with_rng(&mut StdRng::seed_from_u64(42));
+        let again = Signing::new_with_rng(&mut StdRng::seed_from_u64(42));
+        let other = Signing::new_with_rng(&mut StdRng::seed_from_u64(43));
+
+        assert_eq!(signing.public_key(), again.public_key());
+        assert_ne!(signing.public_key(), other.public_key());
     }
 
     #[async_test]
-    async fn private_identity_creation() {
+    async fn master_key_secret_payload() {
         let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
 
-        let master_key = identity.master_key.lock().await;
-        let master_key = master_key.as_ref().unwrap();
+        let payload = master.to_secret_payload();
+        let public_key = &master.public_key;
 
-        assert!(master_key
-            .public_key
-            .verify_subkey(&identity.self_signing_key.lock().await.as_ref().unwrap().public_key,)
-            .is_ok());
+        let restored =
+            MasterSigning::from_secret_payload(user_id().to_owned(), &payload, public_key).unwrap();
+        assert_eq!(restored.inner.public_key(), master.inner.public_key());
 
-        assert!(master_key
-            .public_key
-            .verify_subkey(&identity.user_signing_key.lock().await.as_ref().unwrap().public_key,)
-            .is_ok());
+        let other_payload = crate::utilities::encode(Signing::new().as_bytes());
+        assert!(matches!(
+            MasterSigning::from_secret_payload(user_id().to_owned(), &other_payload, public_key),
+            Err(SecretImportError::MismatchedPublicKeys)
+        ));
+
+        let other_user = user_id!("@other:localhost").to_owned();
+        assert!(matches!(
+            MasterSigning::from_secret_payload(other_user, &payload, public_key),
+            Err(SecretImportError::MismatchedPublicKeys)
+        ));
     }
 
     #[async_test]
-    async fn identity_pickling() {
+    async fn authorized_key_line_round_trip() {
         let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master_key = identity.master_public_key().await.unwrap().get_first_key().unwrap();
 
-        let pickled = identity.pickle().await.unwrap();
+        let line = identity
+            .master_key_authorized_key_line("master key of @example:localhost")
+            .await
+            .unwrap();
+        // Every ssh-ed25519 key blob starts with the same encoded key type.
+        assert!(line.starts_with("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI"));
+        assert!(line.ends_with(" master key of @example:localhost"));
+
+        let (public_key, comment) = parse_authorized_key_line(&line).unwrap();
+        assert_eq!(public_key, master_key);
+        assert_eq!(comment, "master key of @example:localhost");
+
+        let line = identity.master_key_authorized_key_line("").await.unwrap();
+        let (public_key, comment) = parse_authorized_key_line(&line).unwrap();
+        assert_eq!(public_key, master_key);
+        assert!(comment.is_empty());
+
+        let empty = PrivateCrossSigningIdentity::empty(user_id());
+        assert!(empty.master_key_authorized_key_line("").await.is_none());
+
+        let rsa_line = line.replacen("ssh-ed25519", "ssh-rsa", 1);
+        assert!(matches!(
+            parse_authorized_key_line(&rsa_line),
+            Err(SigningError::InvalidAuthorizedKey(_))
+        ));
+        assert!(matches!(
+            parse_authorized_key_line("ssh-ed25519 AAAA"),
+            Err(SigningError::InvalidAuthorizedKey(_))
+        ));
+        assert!(matches!(
+            parse_authorized_key_line("ssh-ed25519 not-base64!"),
+            Err(SigningError::Decode(_))
+        ));
+    }
 
-        let unpickled = PrivateCrossSigningIdentity::from_pickle(pickled).await.unwrap();
+    #[test]
+    fn signing_floats_fails() {
+        let signing = Signing::new();
+        let json = json!({ "user_id": "@example:localhost", "weight": 1.5 });
+
+        assert!(matches!(signing.sign_json(json), Err(SignatureError::CanonicalJson(_))));
+    }
+
+    #[test]
+    fn signing_self_test_detects_broken_canonicalizer() {
+        /// A canonicalizer that adds a trailing newline to the canonical form.
+        struct BrokenCanonicalizer;
+
+        impl Canonicalizer for BrokenCanonicalizer {
+            fn canonicalize(&self, json: Value) -> Result<String, SignatureError> {
+                Ok(format!("{}\n", json))
+            }
+        }
+
+        signing_self_test().unwrap();
+
+        assert!(matches!(
+            signing_self_test_with(&BrokenCanonicalizer),
+            Err(SelfTestError::Verify(SignatureError::VerificationError(_)))
+        ));
+    }
+
+    #[async_test]
+    async fn list_signature_key_ids() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let other_user_id = user_id!("@other:localhost");
+        let other_account = ReadOnlyAccount::new(other_user_id, device_id!("OTHERDEVICE"));
+
+        let signing = Signing::new();
+        let mut key = signing.cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        assert!(signature_key_ids(&key).is_empty());
+
+        account.sign_cross_signing_key(&mut key).await.unwrap();
+        other_account.sign_cross_signing_key(&mut key).await.unwrap();
 
-        assert_eq!(identity.user_id, unpickled.user_id);
-        assert_eq!(&*identity.master_key.lock().await, &*unpickled.master_key.lock().await);
         assert_eq!(
-            &*identity.user_signing_key.lock().await,
-            &*unpickled.user_signing_key.lock().await
+            signature_key_ids(&key),
+            vec![
+                (
+                    user_id().to_owned(),
+                    DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, "DEVICEID".into())
+                ),
+                (
+                    other_user_id.to_owned(),
+                    DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, "OTHERDEVICE".into())
+                ),
+            ]
         );
+    }
+
+    #[test]
+    fn sign_json_only_strips_top_level_fields() {
+        let signing = Signing::new();
+
+        let json = json!({
+            "user_id": "@example:localhost",
+            "Signatures": { "@example:localhost": { "ed25519:DEVICEID": "signature" } },
+            "Unsigned": { "age": 1 },
+            "nested": {
+                "signatures": { "@example:localhost": { "ed25519:DEVICEID": "signature" } },
+                "unsigned": { "age": 2 },
+            },
+            "signatures": { "@example:localhost": { "ed25519:DEVICEID": "signature" } },
+            "unsigned": { "age": 3 },
+        });
+
+        let expected = json!({
+            "user_id": "@example:localhost",
+            "Signatures": { "@example:localhost": { "ed25519:DEVICEID": "signature" } },
+            "Unsigned": { "age": 1 },
+            "nested": {
+                "signatures": { "@example:localhost": { "ed25519:DEVICEID": "signature" } },
+                "unsigned": { "age": 2 },
+            },
+        });
+        let expected: CanonicalJsonValue = expected.try_into().unwrap();
+
         assert_eq!(
-            &*identity.self_signing_key.lock().await,
-            &*unpickled.self_signing_key.lock().await
+            signing.sign_json(json).unwrap().to_base64(),
+            signing.sign(&expected.to_string()).to_base64()
+        );
+    }
+
+    #[cfg(feature = "strict-sign")]
+    #[test]
+    fn strict_signing_rejects_lossy_canonical_json() {
+        /// A canonicalizer that drops the `keys` field.
+        struct LossyCanonicalizer;
+
+        impl Canonicalizer for LossyCanonicalizer {
+            fn canonicalize(&self, mut json: Value) -> Result<String, SignatureError> {
+                json.as_object_mut().unwrap().remove("keys");
+                Ok(serde_json::to_string(&json)?)
+            }
+        }
+
+        let signing = Signing::new();
+        let json = json!({
+            "user_id": "@example:localhost",
+            "keys": { "ed25519:DEVICEID": "key" },
+        });
+
+        assert!(matches!(
+            signing.sign_json_with(json, &LossyCanonicalizer),
+            Err(SignatureError::NonRoundTrippable)
+        ));
+    }
+
+    #[cfg(feature = "strict-sign")]
+    #[test]
+    fn strict_signing_accepts_round_tripping_json() {
+        let signing = Signing::new();
+        let json = json!({
+            "user_id": "@example:localhost",
+            "keys": { "ed25519:DEVICEID": "key" },
+            "counter": 9007199254740991u64,
+            "unicode": "\u{1F600}",
+        });
+
+        let canonical_json: CanonicalJsonValue = json.clone().try_into().unwrap();
+        assert_eq!(
+            signing.sign_json(json).unwrap().to_base64(),
+            signing.sign(&canonical_json.to_string()).to_base64()
+        );
+    }
+
+    #[test]
+    fn signing_integers_at_the_canonical_json_boundaries() {
+        let signing = Signing::new();
+
+        for valid in [9007199254740991i64, -9007199254740991] {
+            let json = json!({ "user_id": "@example:localhost", "counter": valid });
+            signing.sign_json(json).unwrap();
+        }
+
+        for invalid in [9007199254740993i64, -9007199254740993] {
+            let json = json!({ "user_id": "@example:localhost", "counter": invalid });
+            assert!(matches!(signing.sign_json(json), Err(SignatureError::IntegerOutOfRange)));
+        }
+
+        let json = json!({ "user_id": "@example:localhost", "counter": u64::MAX });
+        assert!(matches!(signing.sign_json(json), Err(SignatureError::IntegerOutOfRange)));
+    }
+
+    #[test]
+    fn sign_json_str_rejects_duplicate_keys() {
+        let signing = Signing::new();
+
+        let json = r#"{"user_id":"@example:localhost","keys":{"ed25519:A":"a","ed25519:A":"b"}}"#;
+        assert!(matches!(signing.sign_json_str(json), Err(SignatureError::DuplicateKey)));
+
+        let json = r#"{"user_id":"@example:localhost","user_id":"@other:localhost"}"#;
+        assert!(matches!(signing.sign_json_str(json), Err(SignatureError::DuplicateKey)));
+
+        let json = r#"{"user_id":"@example:localhost"} {"user_id":"@example:localhost"}"#;
+        assert!(matches!(signing.sign_json_str(json), Err(SignatureError::JsonError(_))));
+
+        let json = r#"{"user_id":"@example:localhost","keys":{"ed25519:A":"a"}}"#;
+        assert_eq!(
+            signing.sign_json_str(json).unwrap().to_base64(),
+            signing.sign_json(serde_json::from_str(json).unwrap()).unwrap().to_base64()
         );
+
+        assert!(matches!(signing.sign_json_str("{"), Err(SignatureError::JsonError(_))));
+        assert!(matches!(
+            signing.sign_json_str(r#"{"user_id":"@example:localhost","a":1}x"#),
+            Err(SignatureError::JsonError(_))
+        ));
     }
 
     #[async_test]
-    async fn private_identity_signed_by_account() {
-        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
-        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
-        let master = identity.master_key.lock().await;
-        let master = master.as_ref().unwrap();
+    async fn identity_signs_json_str_with_master_key() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let json = r#"{"user_id":"@example:localhost","keys":{"ed25519:A":"a"}}"#;
 
-        assert!(!master.public_key.signatures().is_empty());
+        let signature = identity.sign_json_str(json).await.unwrap();
+        let expected = identity
+            .master_key
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .inner
+            .sign_json(serde_json::from_str(json).unwrap())
+            .unwrap();
+        assert_eq!(signature.to_base64(), expected.to_base64());
+
+        let duplicate = r#"{"user_id":"@example:localhost","user_id":"@other:localhost"}"#;
+        assert!(matches!(
+            identity.sign_json_str(duplicate).await,
+            Err(SignatureError::DuplicateKey)
+        ));
+        assert!(matches!(
+            PrivateCrossSigningIdentity::empty(user_id()).sign_json_str(json).await,
+            Err(SignatureError::MissingSigningKey)
+        ));
+    }
+
+    #[test]
+    fn signed_only_by_deleted_devices() {
+        let mut master_key =
+            Signing::new().cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        let active_devices = BTreeSet::from([device_id!("ACTIVE").to_owned()]);
+
+        assert!(!signers_are_all_deleted(&master_key, &[], &active_devices));
+
+        let signatures = master_key.signatures.entry(user_id().to_owned()).or_default();
+        signatures.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("DELETED")),
+            "signature".to_owned(),
+        );
+
+        assert!(signers_are_all_deleted(&master_key, &[], &active_devices));
+
+        let signatures = master_key.signatures.entry(user_id().to_owned()).or_default();
+        signatures.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("ACTIVE")),
+            "signature".to_owned(),
+        );
+
+        assert!(!signers_are_all_deleted(&master_key, &[], &active_devices));
     }
 
     #[async_test]
-    async fn sign_device() {
+    async fn master_signature_is_not_a_deleted_device() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master = identity.master_public_key().await.unwrap().get_first_key().unwrap();
+        let mut self_signing = identity.self_signing_public_key().await.unwrap().as_ref().clone();
+        let active_devices = BTreeSet::from([device_id!("ACTIVE").to_owned()]);
+
+        // The subkey only carries the signature of the master key, no device
+        // signed it.
+        assert_eq!(self_signing.signatures.get(user_id()).map(|s| s.len()), Some(1));
+        assert!(!signers_are_all_deleted(&self_signing, &[master], &active_devices));
+
+        let signatures = self_signing.signatures.entry(user_id().to_owned()).or_default();
+        signatures.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("DELETED")),
+            "signature".to_owned(),
+        );
+
+        assert!(signers_are_all_deleted(&self_signing, &[master], &active_devices));
+        assert!(!signers_are_all_deleted(
+            &self_signing,
+            &[master],
+            &BTreeSet::from([device_id!("DELETED").to_owned()])
+        ));
+    }
+
+    #[async_test]
+    async fn missing_signatures_to_upload() {
         let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
-        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+        let mut remote = Signing::new().cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        account.sign_cross_signing_key(&mut remote).await.unwrap();
 
-        let mut device = ReadOnlyDevice::from_account(&account).await;
-        let self_signing = identity.self_signing_key.lock().await;
-        let self_signing = self_signing.as_ref().unwrap();
+        let mut local = remote.clone();
+        assert!(signatures_to_upload(&local, &remote).is_empty());
 
-        let mut device_keys = device.as_device_keys().to_owned();
-        self_signing.sign_device(&mut device_keys).unwrap();
-        device.update_device(&device_keys).unwrap();
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        identity.master_key.lock().await.as_ref().unwrap().sign_subkey(&mut local);
 
-        let public_key = &self_signing.public_key;
-        public_key.verify_device(&device).unwrap()
+        let missing = signatures_to_upload(&local, &remote);
+        let missing = missing.get(user_id()).unwrap();
+        assert_eq!(missing.len(), 1);
+
+        let (key_id, signature) = missing.iter().next().unwrap();
+        assert_eq!(local.signatures.get(user_id()).unwrap().get(key_id), Some(signature));
+        assert!(remote.signatures.get(user_id()).unwrap().get(key_id).is_none());
     }
 
     #[async_test]
-    async fn sign_user_identity() {
+    async fn subkey_binding() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+
+        let mut subkey =
+            identity.self_signing_key.lock().await.as_ref().unwrap().public_key.as_ref().clone();
+        let verify = |subkey: &CrossSigningKey| {
+            master.public_key.verify_subkey(&SelfSigningPubkey::from(subkey.clone()))
+        };
+        verify(&subkey).unwrap();
+
+        let presented = subkey.keys.values_mut().next().unwrap();
+        *presented = SigningKey::Ed25519(Signing::new().public_key());
+
+        assert!(matches!(verify(&subkey), Err(SignatureError::VerificationError(_))));
+    }
+
+    #[async_test]
+    async fn signature_pruning() {
+        use crate::olm::VerifyJson;
+
+        let other_user_id = user_id!("@other:localhost");
         let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
-        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+        let other_account = ReadOnlyAccount::new(other_user_id, device_id!("OTHERDEVICE"));
 
-        let bob_account = ReadOnlyAccount::new(user_id!("@bob:localhost"), device_id!("DEVICEID"));
-        let (bob_private, _, _) = PrivateCrossSigningIdentity::with_account(&bob_account).await;
-        let mut bob_public = ReadOnlyUserIdentity::from_private(&bob_private).await;
+        let mut key = Signing::new().cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        account.sign_cross_signing_key(&mut key).await.unwrap();
+        other_account.sign_cross_signing_key(&mut key).await.unwrap();
 
-        let user_signing = identity.user_signing_key.lock().await;
-        let user_signing = user_signing.as_ref().unwrap();
+        let signing = Signing::new();
+        let signature = signing.sign_json(serde_json::to_value(&key).unwrap()).unwrap();
+        key.signatures
+            .entry(user_id!("@third:localhost").to_owned())
+            .or_default()
+            .insert(signing.key_id(), signature.to_base64());
 
-        let master = user_signing.sign_user(&bob_public).unwrap();
+        assert_eq!(key.signatures.len(), 3);
+        let own_signatures = key.signatures.get(user_id()).unwrap().clone();
 
-        let num_signatures: usize = master.signatures.iter().map(|(_, u)| u.len()).sum();
-        assert_eq!(num_signatures, 1, "We're only uploading our own signature");
+        prune_signatures(&mut key, &BTreeSet::from([user_id().to_owned()]));
 
-        bob_public.master_key = master.into();
+        assert_eq!(key.signatures.len(), 1);
+        assert_eq!(key.signatures.get(user_id()), Some(&own_signatures));
+
+        account
+            .identity_keys()
+            .ed25519
+            .verify_json(
+                user_id(),
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, account.device_id()),
+                &mut serde_json::to_value(&key).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn self_signature_check() {
+        let signing = Signing::new();
+        let mut key = signing.cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        verify_self_signatures(&key).unwrap();
+
+        let signature = signing.sign_json(serde_json::to_value(&key).unwrap()).unwrap();
+        key.signatures
+            .entry(user_id().to_owned())
+            .or_default()
+            .insert(signing.key_id(), signature.to_base64());
+        verify_self_signatures(&key).unwrap();
+
+        let forged = Signing::new().sign_json(serde_json::to_value(&key).unwrap()).unwrap();
+        key.signatures.get_mut(user_id()).unwrap().insert(signing.key_id(), forged.to_base64());
+
+        assert!(matches!(verify_self_signatures(&key), Err(SignatureError::VerificationError(_))));
+    }
+
+    #[async_test]
+    async fn identity_with_forged_self_signature() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let mut own_identity = identity.to_public_identity().await.unwrap();
+        let self_signing = own_identity.self_signing_key().clone();
+        let user_signing = own_identity.user_signing_key().clone();
+
+        let master = identity.master_public_key().await.unwrap();
+        let master_key_id = master.keys().keys().next().unwrap().to_owned();
+
+        let mut forged: CrossSigningKey = master.as_ref().clone();
+        let signature = Signing::new().sign_json(serde_json::to_value(&forged).unwrap()).unwrap();
+        forged
+            .signatures
+            .entry(user_id().to_owned())
+            .or_default()
+            .insert(master_key_id, signature.to_base64());
+
+        assert!(matches!(
+            ReadOnlyOwnUserIdentity::new(
+                forged.clone().into(),
+                self_signing.clone(),
+                user_signing.clone()
+            ),
+            Err(SignatureError::VerificationError(_))
+        ));
+        assert!(matches!(
+            own_identity.update(forged.clone().into(), self_signing.clone(), user_signing),
+            Err(SignatureError::VerificationError(_))
+        ));
+        assert!(matches!(
+            ReadOnlyUserIdentity::new(forged.into(), self_signing),
+            Err(SignatureError::VerificationError(_))
+        ));
+    }
+
+    #[async_test]
+    async fn pickled_signings_format() {
+        let identity = PrivateCrossSigningIdentity::empty(user_id());
+        let pickle = serde_json::to_value(identity.pickle().await.unwrap()).unwrap();
+
+        assert_eq!(
+            pickle,
+            json!({
+                "user_id": "@example:localhost",
+                "shared": false,
+                "keys": {
+                    "format_version": 1,
+                    "master_key": null,
+                    "user_signing_key": null,
+                    "self_signing_key": null,
+                },
+            })
+        );
+
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let pickle = serde_json::to_value(identity.pickle().await.unwrap()).unwrap();
+
+        for name in ["master_key", "user_signing_key", "self_signing_key"] {
+            let fields: BTreeSet<_> =
+                pickle["keys"][name].as_object().unwrap().keys().map(String::as_str).collect();
+            assert_eq!(fields, BTreeSet::from(["pickle", "public_key"]));
+        }
+
+        let mut unversioned = pickle.clone();
+        unversioned["keys"].as_object_mut().unwrap().remove("format_version");
+        PrivateCrossSigningIdentity::from_pickle(serde_json::from_value(unversioned).unwrap())
+            .await
+            .unwrap();
+
+        let mut future = pickle;
+        future["keys"]["format_version"] = 2.into();
+        assert!(matches!(
+            PrivateCrossSigningIdentity::from_pickle(serde_json::from_value(future).unwrap()).await,
+            Err(SigningError::UnsupportedPickleVersion(2))
+        ));
+    }
+
+    #[async_test]
+    async fn pickled_signings_merging() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let mut pickle = identity.pickle().await.unwrap();
+        pickle.keys.user_signing_key = None;
+
+        let mut other = identity.pickle().await.unwrap().keys;
+        other.master_key = None;
+        other.self_signing_key = None;
+
+        pickle.keys.merge(other).unwrap();
+        assert!(pickle.keys.user_signing_key.is_some());
+
+        let restored = PrivateCrossSigningIdentity::from_pickle(pickle).await.unwrap();
+        assert!(restored.status().await.has_user_signing);
+        assert_eq!(restored.master_public_key().await, identity.master_public_key().await);
+
+        let mut pickle = identity.pickle().await.unwrap();
+        pickle.keys.user_signing_key = None;
+
+        let conflicting = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let conflicting = conflicting.pickle().await.unwrap().keys;
+
+        assert!(matches!(
+            pickle.keys.merge(conflicting),
+            Err(SigningError::ConflictingKeys(KeyUsage::Master))
+        ));
+        assert!(pickle.keys.user_signing_key.is_none());
+
+        let mut future = identity.pickle().await.unwrap().keys;
+        future.master_key = None;
+        future.self_signing_key = None;
+        future.format_version = PICKLED_SIGNINGS_FORMAT_VERSION + 1;
+
+        assert!(matches!(
+            pickle.keys.merge(future),
+            Err(SigningError::UnsupportedPickleVersion(v)) if v == PICKLED_SIGNINGS_FORMAT_VERSION + 1
+        ));
+        assert!(pickle.keys.user_signing_key.is_none());
+    }
+
+    #[async_test]
+    async fn pickle_matches_individual_pickles() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let pickle = identity.pickle().await.unwrap();
+
+        let master_key = identity.master_key.lock().await.as_ref().map(|k| k.pickle());
+        let user_signing_key = identity.user_signing_key.lock().await.as_ref().map(|k| k.pickle());
+        let self_signing_key = identity.self_signing_key.lock().await.as_ref().map(|k| k.pickle());
+
+        assert_eq!(
+            serde_json::to_value(pickle.keys).unwrap(),
+            json!({
+                "format_version": PICKLED_SIGNINGS_FORMAT_VERSION,
+                "master_key": master_key,
+                "user_signing_key": user_signing_key,
+                "self_signing_key": self_signing_key,
+            })
+        );
+    }
+
+    #[test]
+    fn raw_signature_verification() {
+        let signing = Signing::new();
+        let content = json!({ "user_id": "@example:localhost", "usage": ["master"] });
+        let signature = signing.sign_json(content.clone()).unwrap().to_base64();
+        let public_key = signing.public_key().to_base64();
+        let key_id = signing.key_id();
+
+        verify_raw(key_id.as_str(), &public_key, &content, &signature).unwrap();
+
+        let other_key = Signing::new().public_key().to_base64();
+        assert!(matches!(
+            verify_raw(key_id.as_str(), &other_key, &content, &signature),
+            Err(SignatureError::VerificationError(_))
+        ));
+
+        assert!(matches!(
+            verify_raw("ed25519", &public_key, &content, &signature),
+            Err(SignatureError::InvalidKeyId(_))
+        ));
+        assert!(matches!(
+            verify_raw("curve25519:DEVICEID", &public_key, &content, &signature),
+            Err(SignatureError::UnsupportedAlgorithm)
+        ));
+        assert!(matches!(
+            verify_raw(key_id.as_str(), "not a key", &content, &signature),
+            Err(SignatureError::InvalidKey(_))
+        ));
+        assert!(verify_raw(key_id.as_str(), &public_key, &content, "not a signature").is_err());
+        assert!(matches!(
+            verify_raw(key_id.as_str(), &public_key, &json!([]), &signature),
+            Err(SignatureError::NotAnObject)
+        ));
+    }
+
+    #[test]
+    fn detached_signature_verification() {
+        let signing = Signing::new();
+        let public_key = signing.public_key().to_base64();
+        let message = "Hello world";
+        let signature = signing.sign(message).to_base64();
+
+        verify_detached(&public_key, message, &signature).unwrap();
+
+        assert!(matches!(
+            verify_detached(&public_key, "Goodbye world", &signature),
+            Err(SignatureError::VerificationError(_))
+        ));
+        assert!(matches!(
+            verify_detached("not base64!", message, &signature),
+            Err(SignatureError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            verify_detached(&crate::utilities::encode([0u8; 16]), message, &signature),
+            Err(SignatureError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            verify_detached(&public_key, message, "not base64!"),
+            Err(SignatureError::VerificationError(_))
+        ));
+        assert!(matches!(
+            verify_detached(&public_key, message, &crate::utilities::encode([0u8; 16])),
+            Err(SignatureError::InvalidSignatureLength { got: 16 })
+        ));
+    }
+
+    #[test]
+    fn signatures_with_invalid_length() {
+        let signing = Signing::new();
+        let public_key = signing.public_key().to_base64();
+        let content = json!({ "user_id": "@example:localhost" });
+        let key_id = signing.key_id();
+
+        for length in [63, 65] {
+            let signature = crate::utilities::encode(vec![0u8; length]);
+
+            assert!(matches!(
+                verify_detached(&public_key, "Hello world", &signature),
+                Err(SignatureError::InvalidSignatureLength { got }) if got == length
+            ));
+            assert!(matches!(
+                verify_raw(key_id.as_str(), &public_key, &content, &signature),
+                Err(SignatureError::InvalidSignatureLength { got }) if got == length
+            ));
+        }
+    }
+
+    #[test]
+    fn versioned_key_ids_match_exactly() {
+        use crate::olm::VerifyJson;
+
+        let signing = Signing::new();
+        let public_key = signing.public_key();
+        let key_name = format!("{}:v2", public_key.to_base64());
+        let versioned =
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, key_name.as_str().into());
+        let other_version = DeviceKeyId::from_parts(
+            DeviceKeyAlgorithm::Ed25519,
+            format!("{}:v3", public_key.to_base64()).as_str().into(),
+        );
+
+        let content = json!({ "user_id": "@example:localhost" });
+        let signature = signing.sign_json(content.clone()).unwrap().to_base64();
+
+        let signatures = BTreeMap::from([(versioned.to_string(), signature.clone())]);
+        let mut signed = content.clone();
+        signed["signatures"] = json!({ "@example:localhost": signatures });
+
+        public_key.verify_json(user_id(), &versioned, &mut signed.clone()).unwrap();
+        verify_raw(versioned.as_str(), &public_key.to_base64(), &content, &signature).unwrap();
+
+        assert!(matches!(
+            public_key.verify_json(user_id(), &other_version, &mut signed.clone()),
+            Err(SignatureError::NoSignatureFound)
+        ));
+        assert!(matches!(
+            public_key.verify_json(user_id(), &signing.key_id(), &mut signed),
+            Err(SignatureError::NoSignatureFound)
+        ));
+        assert!(matches!(
+            verify_raw(&key_name, &public_key.to_base64(), &content, &signature),
+            Err(SignatureError::UnsupportedAlgorithm)
+        ));
+    }
+
+    #[test]
+    fn raw_byte_signature_verification() {
+        fn hex<const N: usize>(input: &str) -> [u8; N] {
+            let bytes: Vec<u8> = (0..input.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&input[i..i + 2], 16).unwrap())
+                .collect();
+
+            bytes.try_into().unwrap()
+        }
+
+        // Test 2 from RFC 8032, section 7.1.
+        let public_key = hex("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c");
+        let signature = hex(concat!(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da",
+            "085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ));
+        let message = [0x72];
+
+        verify_with_bytes(&public_key, &message, &signature).unwrap();
+
+        assert!(matches!(
+            verify_with_bytes(&public_key, b"other message", &signature),
+            Err(SignatureError::VerificationError(_))
+        ));
+
+        let other_key = Signing::new().public_key();
+        assert!(matches!(
+            verify_with_bytes(other_key.as_bytes(), &message, &signature),
+            Err(SignatureError::VerificationError(_))
+        ));
+    }
+
+    #[test]
+    fn signature_key_id_remapping() {
+        let signing = Signing::new();
+        let old_key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, "OLDID".into());
+
+        let mut key = Signing::new().cross_signing_key(user_id().to_owned(), KeyUsage::Master);
+        let signature = signing.sign_json(serde_json::to_value(&key).unwrap()).unwrap();
+        key.signatures
+            .entry(user_id().to_owned())
+            .or_default()
+            .insert(old_key_id.clone(), signature.to_base64());
+
+        let wrong_key_id = Signing::new().key_id();
+        assert!(matches!(
+            remap_signature_key_id(&mut key, &old_key_id, &wrong_key_id),
+            Err(SignatureError::VerificationError(_))
+        ));
+        assert!(key.signatures.get(user_id()).unwrap().contains_key(&old_key_id));
+
+        remap_signature_key_id(&mut key, &old_key_id, &signing.key_id()).unwrap();
+
+        let signatures = key.signatures.get(user_id()).unwrap();
+        assert!(!signatures.contains_key(&old_key_id));
+        assert_eq!(signatures.get(&signing.key_id()), Some(&signature.to_base64()));
+    }
+
+    #[test]
+    fn seed_length_is_checked() {
+        let signing = Signing::new();
+        let restored = Signing::from_seed(signing.as_bytes().to_vec()).unwrap();
+        assert_eq!(restored.public_key(), signing.public_key());
+
+        assert!(matches!(
+            Signing::from_seed(vec![0; 31]),
+            Err(SigningError::InvalidSeedLength { expected: 32, got: 31 })
+        ));
+        assert!(matches!(
+            Signing::from_seed(vec![0; 33]),
+            Err(SigningError::InvalidSeedLength { expected: 32, got: 33 })
+        ));
+    }
+
+    #[async_test]
+    async fn identity_with_derived_subkeys() {
+        let master = Signing::new();
+        let identity = PrivateCrossSigningIdentity::new_with_derived_subkeys(
+            user_id().to_owned(),
+            master.as_bytes().to_vec(),
+        )
+        .unwrap();
+
+        let master_key = identity.master_public_key().await.unwrap();
+        assert_eq!(master_key.get_first_key(), Some(master.public_key()));
+        master_key.verify_subkey(&identity.self_signing_public_key().await.unwrap()).unwrap();
+        master_key.verify_subkey(&identity.user_signing_public_key().await.unwrap()).unwrap();
+
+        let again = PrivateCrossSigningIdentity::new_with_derived_subkeys(
+            user_id().to_owned(),
+            master.as_bytes().to_vec(),
+        )
+        .unwrap();
+        assert_eq!(again.self_signing_public_key().await, identity.self_signing_public_key().await);
+        assert_eq!(again.user_signing_public_key().await, identity.user_signing_public_key().await);
+
+        assert!(matches!(
+            PrivateCrossSigningIdentity::new_with_derived_subkeys(
+                user_id().to_owned(),
+                vec![0; 31]
+            ),
+            Err(SigningError::InvalidSeedLength { expected: 32, got: 31 })
+        ));
+    }
+
+    #[async_test]
+    async fn identity_with_duplicate_public_keys() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master_guard = identity.master_key.lock().await;
+        let master = master_guard.as_ref().unwrap();
+
+        let subkey = Signing::new();
+        let mut self_signing =
+            subkey.cross_signing_key(user_id().to_owned(), KeyUsage::SelfSigning);
+        let mut user_signing =
+            subkey.cross_signing_key(user_id().to_owned(), KeyUsage::UserSigning);
+        master.sign_subkey(&mut self_signing);
+        master.sign_subkey(&mut user_signing);
+
+        assert!(matches!(
+            ReadOnlyOwnUserIdentity::new(
+                master.public_key.clone(),
+                self_signing.clone().into(),
+                user_signing.clone().into()
+            ),
+            Err(SignatureError::DuplicatePublicKey)
+        ));
+
+        let master_key = master.public_key.clone();
+        drop(master_guard);
+        let mut own_identity = identity.to_public_identity().await.unwrap();
+
+        assert!(matches!(
+            own_identity.update(master_key, self_signing.into(), user_signing.into()),
+            Err(SignatureError::DuplicatePublicKey)
+        ));
+    }
+
+    #[test]
+    fn signed_verification_message() {
+        let signing = Signing::new();
+        let key_id = signing.key_id();
+
+        let mut message = json!({
+            "from_device": "DEVICEID",
+            "methods": ["m.sas.v1"],
+            "timestamp": 1_559_598_944_869u64,
+            "transaction_id": "S0meUniqueAndOpaqueString",
+        });
+        let signature = signing.sign_json(message.clone()).unwrap();
+        message["signatures"] = json!({
+            user_id().as_str(): { key_id.as_str(): signature.to_base64() }
+        });
+
+        let verified =
+            verify_verification_message(&signing.public_key(), user_id(), &key_id, &message)
+                .unwrap();
+        assert_eq!(
+            verified,
+            VerifiedMessage { transaction_id: "S0meUniqueAndOpaqueString".to_owned() }
+        );
+
+        message["transaction_id"] = "AnotherTransaction".into();
+        assert!(matches!(
+            verify_verification_message(&signing.public_key(), user_id(), &key_id, &message),
+            Err(SignatureError::VerificationError(_))
+        ));
+
+        let mut message = json!({ "from_device": "DEVICEID" });
+        let signature = signing.sign_json(message.clone()).unwrap();
+        message["signatures"] = json!({
+            user_id().as_str(): { key_id.as_str(): signature.to_base64() }
+        });
+        assert!(matches!(
+            verify_verification_message(&signing.public_key(), user_id(), &key_id, &message),
+            Err(SignatureError::MissingTransactionId)
+        ));
+    }
+
+    #[test]
+    fn canonicalizers_produce_the_same_signature() {
+        /// Relies on serde_json sorting the keys of objects and writing them
+        /// out without any whitespace, which is canonical for ASCII strings.
+        struct SerdeCanonicalizer;
+
+        impl Canonicalizer for SerdeCanonicalizer {
+            fn canonicalize(&self, json: Value) -> Result<String, SignatureError> {
+                Ok(serde_json::to_string(&json)?)
+            }
+        }
+
+        let signing = Signing::new();
+        let json = json!({
+            "user_id": "@example:localhost",
+            "usage": ["master"],
+            "keys": { "ed25519:DEVICEID": "key" },
+            "unsigned": { "device_display_name": "Not signed" },
+        });
+
+        assert_eq!(
+            signing.sign_json(json.clone()).unwrap().to_base64(),
+            signing.sign_json_with(json, &SerdeCanonicalizer).unwrap().to_base64()
+        );
+    }
+
+    #[test]
+    fn verify_json_with_custom_canonicalizer() {
+        use crate::olm::VerifyJson;
+
+        /// Separates the keys and values with a space, which isn't canonical.
+        struct SpacedCanonicalizer;
+
+        impl Canonicalizer for SpacedCanonicalizer {
+            fn canonicalize(&self, json: Value) -> Result<String, SignatureError> {
+                Ok(serde_json::to_string(&json)?.replace(':', ": "))
+            }
+        }
+
+        let signing = Signing::new();
+        let mut json = json!({
+            "user_id": "@example:localhost",
+            "keys": { "ed25519:DEVICEID": "key" },
+        });
+        let signature = signing.sign_json_with(json.clone(), &SpacedCanonicalizer).unwrap();
+        json["signatures"] = json!({
+            user_id().as_str(): { signing.key_id().as_str(): signature.to_base64() }
+        });
+
+        signing
+            .public_key()
+            .verify_json_with(user_id(), &signing.key_id(), &mut json, &SpacedCanonicalizer)
+            .unwrap();
+        assert!(matches!(
+            signing.public_key().verify_json(user_id(), &signing.key_id(), &mut json),
+            Err(SignatureError::VerificationError(_))
+        ));
+    }
+
+    #[async_test]
+    async fn identity_signs_json_with_custom_canonicalizer() {
+        /// Separates the keys and values with a space, which isn't canonical.
+        struct SpacedCanonicalizer;
+
+        impl Canonicalizer for SpacedCanonicalizer {
+            fn canonicalize(&self, json: Value) -> Result<String, SignatureError> {
+                Ok(serde_json::to_string(&json)?.replace(':', ": "))
+            }
+        }
+
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master_key = identity.master_public_key().await.unwrap().get_first_key().unwrap();
+        let key_id = ed25519_key_id(&master_key.to_base64()).unwrap();
+
+        let mut json = json!({ "user_id": user_id(), "device_id": "DEVICEID" });
+        let signature = identity.sign_json_with(json.clone(), &SpacedCanonicalizer).await.unwrap();
+        json["signatures"] = json!({
+            user_id().as_str(): { key_id.as_str(): signature.to_base64() }
+        });
+
+        verify_json_with_canonicalizer(
+            &master_key,
+            user_id(),
+            &key_id,
+            &json,
+            &SpacedCanonicalizer,
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_json_with_canonicalizer(
+                &master_key,
+                user_id(),
+                &key_id,
+                &json,
+                &RumaCanonicalizer
+            ),
+            Err(SignatureError::VerificationError(_))
+        ));
+    }
+
+    #[async_test]
+    async fn master_verifies_subkey() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+
+        let mut subkey =
+            identity.user_signing_key.lock().await.as_ref().unwrap().public_key.as_ref().clone();
+        let verify = |subkey: &CrossSigningKey| {
+            master.public_key.verify_subkey(&UserSigningPubkey::from(subkey.clone()))
+        };
+        verify(&subkey).unwrap();
+
+        subkey.usage.push(KeyUsage::SelfSigning);
+        assert!(matches!(verify(&subkey), Err(SignatureError::VerificationError(_))));
+
+        subkey.signatures.clear();
+        assert!(matches!(verify(&subkey), Err(SignatureError::NoSignatureFound)));
+    }
+
+    #[test]
+    fn subkey_derivation() {
+        let master = Signing::new();
+        let usages = [KeyUsage::SelfSigning, KeyUsage::UserSigning];
+
+        let derived: BTreeSet<_> = usages
+            .iter()
+            .map(|usage| {
+                let subkey = master.derive_subkey(usage.clone()).unwrap();
+                let again = master.derive_subkey(usage.clone()).unwrap();
+                assert_eq!(subkey.public_key(), again.public_key());
+
+                subkey.public_key().to_base64()
+            })
+            .collect();
+
+        assert_eq!(derived.len(), 2);
+        assert!(!derived.contains(&master.public_key().to_base64()));
+        assert!(matches!(
+            master.derive_subkey(KeyUsage::Master),
+            Err(SigningError::UnsupportedKeyUsage(KeyUsage::Master))
+        ));
+        assert!(matches!(
+            master.derive_subkey(KeyUsage::from("m.unknown")),
+            Err(SigningError::UnsupportedKeyUsage(_))
+        ));
+    }
+
+    #[test]
+    fn signing_equality() {
+        let signing = Signing::new();
+        let copy = Signing::from_pickle(signing.pickle()).unwrap();
+
+        assert_eq!(signing, copy);
+        assert_ne!(signing, Signing::new());
+    }
+
+    #[test]
+    fn key_usage_spec_strings() {
+        use crate::olm::VerifyJson;
+
+        let signing = Signing::new();
+
+        for (usage, name) in [
+            (KeyUsage::Master, "master"),
+            (KeyUsage::SelfSigning, "self_signing"),
+            (KeyUsage::UserSigning, "user_signing"),
+        ] {
+            let key = signing.cross_signing_key(user_id().to_owned(), usage);
+            let mut json = serde_json::to_value(&key).unwrap();
+            assert_eq!(json["usage"], json!([name]));
+
+            let signature = signing.sign_json(json.clone()).unwrap();
+            json["signatures"] = json!({
+                user_id().as_str(): { signing.key_id().as_str(): signature.to_base64() }
+            });
+            assert_eq!(json["usage"], json!([name]));
+
+            signing.public_key().verify_json(user_id(), &signing.key_id(), &mut json).unwrap();
+        }
+    }
+
+    #[test]
+    fn key_usage_guard() {
+        for usage in [KeyUsage::Master, KeyUsage::SelfSigning, KeyUsage::UserSigning] {
+            assert!(usage_serializes_to_spec_name(&usage));
+        }
+
+        assert!(usage_serializes_to_spec_name(&KeyUsage::from("m.unknown")));
+    }
+
+    #[test]
+    fn seed_export_roundtrip() {
+        let signing = Signing::new();
+        let seed = signing.export_seed();
+
+        let restored = Signing::from_seed(seed.to_vec()).unwrap();
+        assert_eq!(restored.public_key(), signing.public_key());
+        assert_eq!(restored.export_seed(), seed);
+    }
+
+    #[test]
+    fn verbose_json_verification() {
+        let signing = Signing::new();
+        let json = json!({
+            "user_id": "@example:localhost",
+            "keys": { "ed25519:DEVICEID": "key" },
+            "unsigned": { "device_display_name": "Not signed" },
+        });
+        let signature = signing.sign_json(json.clone()).unwrap();
+
+        let canonical_json = verify_json_verbose(&signing.public_key(), &json, &signature).unwrap();
+        assert_eq!(
+            &*canonical_json,
+            r#"{"keys":{"ed25519:DEVICEID":"key"},"user_id":"@example:localhost"}"#
+        );
+        assert_eq!(signing.sign(&canonical_json).to_base64(), signature.to_base64());
+
+        let other_key = Signing::new().public_key();
+        match verify_json_verbose(&other_key, &json, &signature) {
+            Err(SignatureError::CanonicalJsonVerificationError { canonical_json: c, .. }) => {
+                assert_eq!(c, canonical_json)
+            }
+            _ => panic!("The signature shouldn't verify under a different key"),
+        }
+    }
+
+    #[async_test]
+    async fn backup_auth_data_signing() {
+        use crate::olm::VerifyJson;
+
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let mut auth_data = json!({
+            "public_key": "hSDwCYkwp1R0i33ctD73Wg2/Og0mOBr066SpjqqbTmo",
+        });
+        let device_signature = account.sign_json(auth_data.clone()).await;
+        auth_data["signatures"] = json!({
+            user_id().as_str(): { "ed25519:DEVICEID": device_signature.to_base64() }
+        });
+
+        identity.sign_backup_auth_data(&mut auth_data).await.unwrap();
+
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+        assert_eq!(auth_data["signatures"][user_id().as_str()].as_object().unwrap().len(), 2);
+        master
+            .inner
+            .public_key()
+            .verify_json(user_id(), &master.inner.key_id(), &mut auth_data)
+            .unwrap();
+        account
+            .identity_keys()
+            .ed25519
+            .verify_json(
+                user_id(),
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, account.device_id()),
+                &mut auth_data,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn key_id_from_external_key() {
+        let public_key = Signing::new().public_key().to_base64();
+        let key_id = ed25519_key_id(&public_key).unwrap();
+        assert_eq!(key_id.to_string(), format!("ed25519:{}", public_key));
+
+        let long_key = "A".repeat(4096);
+        assert!(matches!(
+            ed25519_key_id(&long_key),
+            Err(SignatureError::InvalidPublicKeyLength { expected: 43, got: 4096 })
+        ));
+
+        let padded_key = format!("{}=", public_key);
+        assert!(matches!(
+            ed25519_key_id(&padded_key),
+            Err(SignatureError::InvalidPublicKeyLength { expected: 43, got: 44 })
+        ));
+
+        let not_base64 = "!".repeat(43);
+        assert!(matches!(ed25519_key_id(&not_base64), Err(SignatureError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn verify_against_candidate_keys() {
+        let signing = Signing::new();
+        let json = json!({
+            "user_id": "@example:localhost",
+            "unsigned": { "age": 1 },
+        });
+        let signature = signing.sign_json(json.clone()).unwrap();
+
+        let candidates =
+            [Signing::new().public_key(), signing.public_key(), Signing::new().public_key()];
+        assert_eq!(verify_against_any(&json, &signature, &candidates).unwrap(), 1);
+
+        assert!(verify_against_any(&json, &signature, &candidates[..1]).is_err());
+        assert!(verify_against_any(&json, &signature, &[]).is_err());
+    }
+
+    #[test]
+    fn pickling_signing() {
+        let signing = Signing::new();
+        let pickled = signing.pickle();
+
+        let unpickled = Signing::from_pickle(pickled).unwrap();
+
+        assert_eq!(signing.public_key(), unpickled.public_key());
+    }
+
+    #[test]
+    fn pickling_signing_expecting_public_key() {
+        let signing = Signing::new();
+
+        let unpickled =
+            Signing::from_pickle_expecting(signing.pickle(), &signing.public_key()).unwrap();
+        assert_eq!(signing.public_key(), unpickled.public_key());
+
+        let other = Signing::new();
+        assert!(matches!(
+            Signing::from_pickle_expecting(signing.pickle(), &other.public_key()),
+            Err(SigningError::PublicKeyMismatch)
+        ));
+    }
+
+    #[async_test]
+    async fn unpickling_master_key_checks_public_key() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let other = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+        let other_master = other.master_key.lock().await;
+        let other_master = other_master.as_ref().unwrap();
+
+        let pickle = serde_json::to_value(master.pickle()).unwrap();
+        let unpickled = MasterSigning::from_pickle(serde_json::from_value(pickle.clone()).unwrap());
+        assert_eq!(&unpickled.unwrap(), master);
+
+        let mut mismatched = pickle;
+        mismatched["public_key"] = serde_json::to_value(other_master.public_key.as_ref()).unwrap();
+
+        assert!(matches!(
+            MasterSigning::from_pickle(serde_json::from_value(mismatched).unwrap()),
+            Err(SigningError::PublicKeyMismatch)
+        ));
+    }
+
+    #[cfg(feature = "legacy-compat")]
+    #[test]
+    fn verify_signature_including_unsigned() {
+        use super::pk_signing::verify_json_legacy_unsigned;
+        use crate::olm::VerifyJson;
+
+        let signing = Signing::new();
+        let key_id = DeviceKeyId::from_parts(
+            DeviceKeyAlgorithm::Ed25519,
+            signing.public_key().to_base64().as_str().into(),
+        );
+
+        let mut json = json!({
+            "user_id": "@example:localhost",
+            "unsigned": { "device_display_name": "Old client" },
+        });
+
+        let canonical_json: CanonicalJsonValue = json.clone().try_into().unwrap();
+        let signature = signing.sign(&canonical_json.to_string());
+
+        json["signatures"] = json!({
+            "@example:localhost": { key_id.to_string(): signature.to_base64() }
+        });
+
+        assert!(signing.public_key().verify_json(user_id(), &key_id, &mut json.clone()).is_err());
+        verify_json_legacy_unsigned(&signing.public_key(), user_id(), &key_id, &json).unwrap();
+
+        json["unsigned"] = json!({ "device_display_name": "Tampered" });
+        assert!(
+            verify_json_legacy_unsigned(&signing.public_key(), user_id(), &key_id, &json).is_err()
+        );
+    }
+
+    #[cfg(all(feature = "legacy-compat", feature = "metrics"))]
+    #[test]
+    fn legacy_verification_stats() {
+        use super::pk_signing::verify_json_legacy_unsigned;
+        use crate::olm::VerificationStats;
+
+        let signing = Signing::new();
+        let key_id = DeviceKeyId::from_parts(
+            DeviceKeyAlgorithm::Ed25519,
+            signing.public_key().to_base64().as_str().into(),
+        );
+
+        let mut json = json!({
+            "user_id": "@example:localhost",
+            "unsigned": { "device_display_name": "Old client" },
+        });
+
+        let canonical_json: CanonicalJsonValue = json.clone().try_into().unwrap();
+        let signature = signing.sign(&canonical_json.to_string());
+
+        json["signatures"] = json!({
+            "@example:localhost": { key_id.to_string(): signature.to_base64() }
+        });
+
+        let before = VerificationStats::snapshot();
+        verify_json_legacy_unsigned(&signing.public_key(), user_id(), &key_id, &json).unwrap();
+        let after = VerificationStats::snapshot();
+
+        // Other tests might verify signatures concurrently, so the counters
+        // can only be checked for a lower bound.
+        assert!(after.valid > before.valid);
+    }
+
+    #[async_test]
+    async fn private_identity_creation() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let master_key = identity.master_key.lock().await;
+        let master_key = master_key.as_ref().unwrap();
+
+        assert!(master_key
+            .public_key
+            .verify_subkey(&identity.self_signing_key.lock().await.as_ref().unwrap().public_key,)
+            .is_ok());
+
+        assert!(master_key
+            .public_key
+            .verify_subkey(&identity.user_signing_key.lock().await.as_ref().unwrap().public_key,)
+            .is_ok());
+    }
+
+    #[async_test]
+    async fn identity_pickling() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+
+        let pickled = identity.pickle().await.unwrap();
+
+        let unpickled = PrivateCrossSigningIdentity::from_pickle(pickled).await.unwrap();
+
+        assert_eq!(identity.user_id, unpickled.user_id);
+        assert_eq!(&*identity.master_key.lock().await, &*unpickled.master_key.lock().await);
+        assert_eq!(
+            &*identity.user_signing_key.lock().await,
+            &*unpickled.user_signing_key.lock().await
+        );
+        assert_eq!(
+            &*identity.self_signing_key.lock().await,
+            &*unpickled.self_signing_key.lock().await
+        );
+    }
+
+    #[async_test]
+    async fn master_key_public_identity() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master_guard = identity.master_key.lock().await;
+        let master_key = master_guard.as_ref().unwrap();
+
+        let public_identity = master_key.public_identity();
+        assert_eq!(public_identity.user_id, user_id());
+        assert_eq!(public_identity.master_key, master_key.inner.public_key());
+        assert_eq!(public_identity.fingerprint.split(' ').count(), 16);
+
+        let serialized = serde_json::to_value(&public_identity).unwrap();
+        let fields: Vec<&str> =
+            serialized.as_object().unwrap().keys().map(|k| k.as_str()).collect();
+        assert_eq!(fields, ["fingerprint", "master_key", "user_id"]);
+        assert!(!serialized.to_string().contains(&master_key.export_seed()));
+
+        let deserialized: PublicIdentity = serde_json::from_value(serialized).unwrap();
+        assert_eq!(public_identity, deserialized);
+
+        drop(master_guard);
+        assert_eq!(identity.public_identity().await, Some(public_identity));
+        assert_eq!(PrivateCrossSigningIdentity::empty(user_id()).public_identity().await, None);
+    }
+
+    #[async_test]
+    async fn public_key_id_matches_signing_key_id() {
+        let identity = PrivateCrossSigningIdentity::new(user_id().to_owned()).await;
+        let master_key = identity.master_key.lock().await;
+        let master_key = master_key.as_ref().unwrap();
+        let self_signing = identity.self_signing_key.lock().await;
+        let self_signing = self_signing.as_ref().unwrap();
+
+        let key_id = master_key.public_key.as_device_key_id().unwrap();
+        assert_eq!(key_id, master_key.inner.key_id());
+        assert!(self_signing
+            .public_key
+            .as_ref()
+            .signatures
+            .get(user_id())
+            .unwrap()
+            .contains_key(&key_id));
+
+        assert_eq!(
+            self_signing.public_key.as_device_key_id().unwrap(),
+            self_signing.inner.key_id()
+        );
+    }
+
+    #[async_test]
+    async fn private_identity_signed_by_account() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+
+        assert!(!master.public_key.signatures().is_empty());
+    }
+
+    #[async_test]
+    async fn sign_device() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+
+        let mut device = ReadOnlyDevice::from_account(&account).await;
+        let self_signing = identity.self_signing_key.lock().await;
+        let self_signing = self_signing.as_ref().unwrap();
+
+        let mut device_keys = device.as_device_keys().to_owned();
+        self_signing.sign_device(&mut device_keys).unwrap();
+        device.update_device(&device_keys).unwrap();
+
+        let public_key = &self_signing.public_key;
+        public_key.verify_device(&device).unwrap()
+    }
+
+    #[async_test]
+    async fn sign_device_of_other_user() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+
+        let other_account =
+            ReadOnlyAccount::new(user_id!("@other:localhost"), device_id!("OTHERDEVICE"));
+        let device = ReadOnlyDevice::from_account(&other_account).await;
+        let self_signing = identity.self_signing_key.lock().await;
+        let self_signing = self_signing.as_ref().unwrap();
+
+        let mut device_keys = device.as_device_keys().to_owned();
+        self_signing.sign_device(&mut device_keys).unwrap();
+
+        assert!(matches!(
+            self_signing.public_key.verify_device_keys(device_keys),
+            Err(SignatureError::SignerContentMismatch)
+        ));
+    }
+
+    #[async_test]
+    async fn sign_device_returns_key_id() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+        let self_signing = identity.self_signing_key.lock().await;
+        let self_signing = self_signing.as_ref().unwrap();
+
+        let mut device_keys =
+            ReadOnlyDevice::from_account(&account).await.as_device_keys().to_owned();
+        let key_id = self_signing.sign_device_with_id(&mut device_keys).unwrap();
+
+        assert_eq!(key_id, self_signing.inner.key_id());
+        assert!(device_keys.signatures.get(user_id()).unwrap().contains_key(&key_id));
+        self_signing.public_key.verify_device_keys(device_keys).unwrap();
+    }
+
+    #[async_test]
+    async fn sign_user_identity() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+
+        let bob_account = ReadOnlyAccount::new(user_id!("@bob:localhost"), device_id!("DEVICEID"));
+        let (bob_private, _, _) = PrivateCrossSigningIdentity::with_account(&bob_account).await;
+        let mut bob_public = ReadOnlyUserIdentity::from_private(&bob_private).await;
+
+        let user_signing = identity.user_signing_key.lock().await;
+        let user_signing = user_signing.as_ref().unwrap();
+
+        let master = user_signing.sign_user(&bob_public).unwrap();
+
+        let num_signatures: usize = master.signatures.iter().map(|(_, u)| u.len()).sum();
+        assert_eq!(num_signatures, 1, "We're only uploading our own signature");
+
+        bob_public.master_key = master.into();
+
+        user_signing.public_key.verify_master_key(bob_public.master_key()).unwrap();
+    }
+
+    #[async_test]
+    async fn resign_all_replaces_stale_signatures() {
+        let account = ReadOnlyAccount::new(user_id(), device_id!("DEVICEID"));
+        let second_account = ReadOnlyAccount::new(user_id(), device_id!("SECONDDEVICE"));
+        let (identity, _, _) = PrivateCrossSigningIdentity::with_account(&account).await;
+
+        let bob_account = ReadOnlyAccount::new(user_id!("@bob:localhost"), device_id!("DEVICEID"));
+        let (bob_private, _, _) = PrivateCrossSigningIdentity::with_account(&bob_account).await;
+        let mut bob_public = ReadOnlyUserIdentity::from_private(&bob_private).await;
+
+        let stale = Signing::new().sign("stale").to_base64();
+
+        let (mut devices, mut subkeys) = {
+            let master = identity.master_key.lock().await;
+            let master = master.as_ref().unwrap();
+            let self_signing = identity.self_signing_key.lock().await;
+            let self_signing = self_signing.as_ref().unwrap();
+
+            let mut stale_device = account.unsigned_device_keys();
+            stale_device
+                .signatures
+                .entry(user_id().to_owned())
+                .or_default()
+                .insert(self_signing.inner.key_id(), stale.clone());
+
+            let mut fresh_device = second_account.unsigned_device_keys();
+            self_signing.sign_device(&mut fresh_device).unwrap();
+
+            let mut stale_subkey = self_signing.public_key.as_ref().clone();
+            stale_subkey
+                .signatures
+                .get_mut(user_id())
+                .unwrap()
+                .insert(master.inner.key_id(), stale.clone());
+
+            let bob_master = bob_public.master_key().as_ref().clone();
+
+            (vec![stale_device, fresh_device], vec![stale_subkey, bob_master])
+        };
+
+        let report = identity.resign_all(&mut devices, &mut subkeys).await.unwrap();
+        assert_eq!(report, ResignReport { changed: 3, unchanged: 1 });
+
+        let master = identity.master_key.lock().await;
+        let master = master.as_ref().unwrap();
+        let self_signing = identity.self_signing_key.lock().await;
+        let self_signing = self_signing.as_ref().unwrap();
+        let user_signing = identity.user_signing_key.lock().await;
+        let user_signing = user_signing.as_ref().unwrap();
+
+        for device_keys in devices {
+            self_signing.public_key.verify_device_keys(device_keys).unwrap();
+        }
+
+        master.public_key.verify_subkey(&SelfSigningPubkey::from(subkeys[0].clone())).unwrap();
 
+        bob_public.master_key = subkeys[1].clone().into();
         user_signing.public_key.verify_master_key(bob_public.master_key()).unwrap();
     }
 }
@@ -12,21 +12,38 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, convert::TryInto};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    sync::Arc,
+};
 
+use base64::{decode_config, encode_config, STANDARD};
+use hkdf::Hkdf;
+use rand::{thread_rng, CryptoRng, RngCore};
 use ruma::{
-    encryption::KeyUsage, serde::CanonicalJsonValue, DeviceKeyAlgorithm, DeviceKeyId, OwnedUserId,
+    encryption::KeyUsage, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId,
+    OwnedUserId, UserId,
+};
+use serde::{
+    de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::{Error as JsonError, Value};
+use serde_json::{json, Error as JsonError, Map, Number, Value};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
 use thiserror::Error;
 use vodozemac::{Ed25519PublicKey, Ed25519SecretKey, Ed25519Signature, KeyError};
+use zeroize::Zeroizing;
 
 use crate::{
     error::SignatureError,
     identities::{MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
-    types::{CrossSigningKey, CrossSigningKeySignatures, DeviceKeys},
-    utilities::{encode, DecodeError},
+    olm::utility::{check_integer_range, decode_signature, Canonicalizer, RumaCanonicalizer},
+    store::SecretImportError,
+    types::{CrossSigningKey, CrossSigningKeySignatures, DeviceKeys, SigningKey},
+    utilities::{encode, fingerprint, DecodeError},
     ReadOnlyUserIdentity,
 };
 
@@ -44,6 +61,60 @@ pub enum SigningError {
     /// Error deserializing the pickle data.
     #[error(transparent)]
     Json(#[from] JsonError),
+
+    /// The secret key material isn't a valid Ed25519 key.
+    #[error(transparent)]
+    Key(#[from] KeyError),
+
+    /// The restored signing key doesn't match the expected public key.
+    #[error("The restored signing key doesn't match the expected public key")]
+    PublicKeyMismatch,
+
+    /// The key usage isn't one of the cross signing subkey usages, i.e. self
+    /// signing or user signing.
+    #[error("The key usage {0:?} isn't a cross signing subkey usage")]
+    UnsupportedKeyUsage(KeyUsage),
+
+    /// The authorized keys line isn't a valid `ssh-ed25519` key.
+    #[error("Invalid authorized keys line: {0}")]
+    InvalidAuthorizedKey(&'static str),
+
+    /// The pickled signing keys use a format version we don't support.
+    #[error("The pickle format version {0} isn't supported")]
+    UnsupportedPickleVersion(u32),
+
+    /// The seed of the signing key has an invalid length.
+    #[error("The seed has an invalid length, expected {expected}, got {got}")]
+    InvalidSeedLength {
+        /// The length the seed should have.
+        expected: usize,
+        /// The length the seed actually had.
+        got: usize,
+    },
+
+    /// Two pickles that are being merged contain differing keys.
+    #[error("The pickles contain differing {0:?} keys")]
+    ConflictingKeys(KeyUsage),
+}
+
+/// Error type reporting which step of the signing self test failed.
+#[derive(Debug, Error)]
+pub enum SelfTestError {
+    /// The known object couldn't be signed.
+    #[error("the self test object couldn't be signed: {0}")]
+    Sign(SignatureError),
+
+    /// The signing key couldn't be pickled or unpickled.
+    #[error(transparent)]
+    Pickle(#[from] SigningError),
+
+    /// The unpickled signing key has a different public key.
+    #[error("the public key changed while pickling the signing key")]
+    PublicKeyMismatch,
+
+    /// The signature didn't verify under the unpickled signing key.
+    #[error("the self test signature couldn't be verified: {0}")]
+    Verify(SignatureError),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -59,8 +130,16 @@ impl std::fmt::Debug for Signing {
 }
 
 impl PartialEq for Signing {
+    /// Keys are compared by their public part, in constant time, like
+    /// `from_pickle_expecting` does.
     fn eq(&self, other: &Signing) -> bool {
-        self.public_key == other.public_key
+        self.public_key.as_bytes()[..].ct_eq(&other.public_key.as_bytes()[..]).into()
+    }
+}
+
+impl Default for Signing {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -70,6 +149,24 @@ pub struct MasterSigning {
     pub public_key: MasterPubkey,
 }
 
+/// The public, safe to share, part of a master key.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PublicIdentity {
+    /// The user id of the master key's owner.
+    pub user_id: OwnedUserId,
+    /// The public part of the master key.
+    pub master_key: Ed25519PublicKey,
+    /// The master key formatted as a fingerprint, to be compared by users.
+    pub fingerprint: String,
+}
+
+/// A signed verification message whose signature was successfully verified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedMessage {
+    /// The id of the verification flow the message belongs to.
+    pub transaction_id: String,
+}
+
 #[derive(Deserialize, Serialize)]
 #[allow(missing_debug_implementations)]
 pub struct PickledMasterSigning {
@@ -99,7 +196,31 @@ impl MasterSigning {
     }
 
     pub fn export_seed(&self) -> String {
-        encode(self.inner.as_bytes())
+        encode(&*self.inner.export_seed())
+    }
+
+    /// Get the public part of the master key in a form that can be shared out
+    /// of band, it contains no secret material.
+    pub fn public_identity(&self) -> PublicIdentity {
+        let master_key = self.inner.public_key();
+
+        PublicIdentity {
+            user_id: self.public_key.user_id().to_owned(),
+            master_key,
+            fingerprint: fingerprint(&master_key),
+        }
+    }
+
+    /// Render the public part of the master key as a line of an SSH
+    /// `authorized_keys` file, i.e. `ssh-ed25519 <base64 key blob> <comment>`.
+    pub fn to_authorized_key_line(&self, comment: &str) -> String {
+        let blob = encode_config(ssh_public_key_blob(&self.inner.public_key()), STANDARD);
+
+        if comment.is_empty() {
+            format!("{} {}", SSH_ED25519_KEY_TYPE, blob)
+        } else {
+            format!("{} {} {}", SSH_ED25519_KEY_TYPE, blob, comment)
+        }
     }
 
     pub fn from_base64(user_id: OwnedUserId, key: &str) -> Result<Self, KeyError> {
@@ -109,8 +230,35 @@ impl MasterSigning {
         Ok(Self { inner, public_key })
     }
 
+    /// Get the payload that shares this key with our other devices using a
+    /// `m.secret.send` event.
+    ///
+    /// The payload is the unpadded base64 encoded seed of the key.
+    pub fn to_secret_payload(&self) -> String {
+        self.export_seed()
+    }
+
+    /// Restore the master key from the payload of a `m.secret.send` event.
+    ///
+    /// The master key of the given user that gets restored needs to match the
+    /// given public master key, otherwise
+    /// `SecretImportError::MismatchedPublicKeys` is returned.
+    pub fn from_secret_payload(
+        user_id: OwnedUserId,
+        payload: &str,
+        public_key: &MasterPubkey,
+    ) -> Result<Self, SecretImportError> {
+        let master = Self::from_base64(user_id, payload)?;
+
+        if &master.public_key == public_key {
+            Ok(master)
+        } else {
+            Err(SecretImportError::MismatchedPublicKeys)
+        }
+    }
+
     pub fn from_pickle(pickle: PickledMasterSigning) -> Result<Self, SigningError> {
-        let inner = Signing::from_pickle(pickle.pickle)?;
+        let inner = Signing::from_cross_signing_pickle(pickle.pickle, &pickle.public_key)?;
 
         Ok(Self { inner, public_key: pickle.public_key.into() })
     }
@@ -127,13 +275,32 @@ impl MasterSigning {
             .signatures
             .entry(self.public_key.user_id().to_owned())
             .or_insert_with(BTreeMap::new)
-            .insert(
-                DeviceKeyId::from_parts(
-                    DeviceKeyAlgorithm::Ed25519,
-                    self.inner.public_key.to_base64().as_str().into(),
-                ),
-                signature.to_base64(),
-            );
+            .insert(self.inner.key_id(), signature.to_base64());
+    }
+
+    /// Sign the `auth_data` of a `m.megolm_backup.v1.curve25519-aes-sha2` key
+    /// backup.
+    ///
+    /// The signature is added to the `signatures` of the `auth_data`, next to
+    /// any signatures it already carries.
+    pub fn sign_backup_auth_data(&self, auth_data: &mut Value) -> Result<(), SignatureError> {
+        let signature = self.inner.sign_json(auth_data.clone())?;
+
+        let auth_data = auth_data.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+        let signatures = auth_data
+            .entry("signatures")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .ok_or(SignatureError::NotAnObject)?;
+
+        signatures
+            .entry(self.public_key.user_id().as_str())
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .ok_or(SignatureError::NotAnObject)?
+            .insert(self.inner.key_id().to_string(), signature.to_base64().into());
+
+        Ok(())
     }
 }
 
@@ -145,7 +312,7 @@ impl UserSigning {
     }
 
     pub fn export_seed(&self) -> String {
-        encode(self.inner.as_bytes())
+        encode(&*self.inner.export_seed())
     }
 
     pub fn from_base64(user_id: OwnedUserId, key: &str) -> Result<Self, KeyError> {
@@ -179,19 +346,13 @@ impl UserSigning {
         signatures
             .entry(self.public_key.user_id().to_owned())
             .or_insert_with(BTreeMap::new)
-            .insert(
-                DeviceKeyId::from_parts(
-                    DeviceKeyAlgorithm::Ed25519,
-                    self.inner.public_key.to_base64().as_str().into(),
-                ),
-                signature.to_base64(),
-            );
+            .insert(self.inner.key_id(), signature.to_base64());
 
         Ok(signatures)
     }
 
     pub fn from_pickle(pickle: PickledUserSigning) -> Result<Self, SigningError> {
-        let inner = Signing::from_pickle(pickle.pickle)?;
+        let inner = Signing::from_cross_signing_pickle(pickle.pickle, &pickle.public_key)?;
 
         Ok(Self { inner, public_key: pickle.public_key.into() })
     }
@@ -205,7 +366,7 @@ impl SelfSigning {
     }
 
     pub fn export_seed(&self) -> String {
-        encode(self.inner.as_bytes())
+        encode(&*self.inner.export_seed())
     }
 
     pub fn from_base64(user_id: OwnedUserId, key: &str) -> Result<Self, KeyError> {
@@ -220,21 +381,29 @@ impl SelfSigning {
     }
 
     pub fn sign_device(&self, device_keys: &mut DeviceKeys) -> Result<(), SignatureError> {
+        self.sign_device_with_id(device_keys).map(|_| ())
+    }
+
+    /// Sign the given device keys and return the id of the key the signature
+    /// was filed under.
+    pub fn sign_device_with_id(
+        &self,
+        device_keys: &mut DeviceKeys,
+    ) -> Result<OwnedDeviceKeyId, SignatureError> {
         let signature = self.sign_device_helper(serde_json::to_value(&device_keys)?)?;
+        let key_id = self.inner.key_id();
 
-        device_keys.signatures.entry(self.public_key.user_id().to_owned()).or_default().insert(
-            DeviceKeyId::from_parts(
-                DeviceKeyAlgorithm::Ed25519,
-                self.inner.public_key.to_base64().as_str().into(),
-            ),
-            signature.to_base64(),
-        );
+        device_keys
+            .signatures
+            .entry(self.public_key.user_id().to_owned())
+            .or_default()
+            .insert(key_id.clone(), signature.to_base64());
 
-        Ok(())
+        Ok(key_id)
     }
 
     pub fn from_pickle(pickle: PickledSelfSigning) -> Result<Self, SigningError> {
-        let inner = Signing::from_pickle(pickle.pickle)?;
+        let inner = Signing::from_cross_signing_pickle(pickle.pickle, &pickle.public_key)?;
 
         Ok(Self { inner, public_key: pickle.public_key.into() })
     }
@@ -252,20 +421,111 @@ pub struct UserSigning {
     pub public_key: UserSigningPubkey,
 }
 
+/// The current version of the serialized form of `PickledSignings`.
+///
+/// This needs to be bumped whenever the serialized form changes.
+pub const PICKLED_SIGNINGS_FORMAT_VERSION: u32 = 1;
+
+/// Pickles created before the format was versioned use the first version.
+fn unversioned_format() -> u32 {
+    1
+}
+
 #[derive(Serialize, Deserialize)]
 #[allow(missing_debug_implementations)]
 pub struct PickledSignings {
+    #[serde(default = "unversioned_format")]
+    pub format_version: u32,
     pub master_key: Option<PickledMasterSigning>,
     pub user_signing_key: Option<PickledUserSigning>,
     pub self_signing_key: Option<PickledSelfSigning>,
 }
 
+impl PickledSignings {
+    /// Pickle the given signing keys.
+    ///
+    /// This is the same as pickling each of the keys on its own and putting
+    /// the pickles together.
+    pub fn from_signings(
+        master_key: Option<&MasterSigning>,
+        user_signing_key: Option<&UserSigning>,
+        self_signing_key: Option<&SelfSigning>,
+    ) -> Self {
+        Self {
+            format_version: PICKLED_SIGNINGS_FORMAT_VERSION,
+            master_key: master_key.map(|k| k.pickle()),
+            user_signing_key: user_signing_key.map(|k| k.pickle()),
+            self_signing_key: self_signing_key.map(|k| k.pickle()),
+        }
+    }
+
+    /// Fill in the keys that are missing from this pickle with the keys of the
+    /// other pickle.
+    ///
+    /// Both pickles need to have the same format version, otherwise an
+    /// `UnsupportedPickleVersion` error with the version of the other pickle is
+    /// returned. If both pickles contain a key with the same usage, the public
+    /// keys need to match, otherwise a `ConflictingKeys` error is returned. In
+    /// both cases this pickle is left untouched.
+    pub fn merge(&mut self, other: PickledSignings) -> Result<(), SigningError> {
+        fn check(
+            ours: Option<&CrossSigningKey>,
+            theirs: Option<&CrossSigningKey>,
+            usage: KeyUsage,
+        ) -> Result<(), SigningError> {
+            match (ours, theirs) {
+                (Some(ours), Some(theirs)) if ours.keys != theirs.keys => {
+                    Err(SigningError::ConflictingKeys(usage))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        if other.format_version != self.format_version {
+            return Err(SigningError::UnsupportedPickleVersion(other.format_version));
+        }
+
+        check(
+            self.master_key.as_ref().map(|k| &k.public_key),
+            other.master_key.as_ref().map(|k| &k.public_key),
+            KeyUsage::Master,
+        )?;
+        check(
+            self.user_signing_key.as_ref().map(|k| &k.public_key),
+            other.user_signing_key.as_ref().map(|k| &k.public_key),
+            KeyUsage::UserSigning,
+        )?;
+        check(
+            self.self_signing_key.as_ref().map(|k| &k.public_key),
+            other.self_signing_key.as_ref().map(|k| &k.public_key),
+            KeyUsage::SelfSigning,
+        )?;
+
+        self.master_key = self.master_key.take().or(other.master_key);
+        self.user_signing_key = self.user_signing_key.take().or(other.user_signing_key);
+        self.self_signing_key = self.self_signing_key.take().or(other.self_signing_key);
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PickledSigning(Ed25519SecretKey);
 
 impl Signing {
     pub fn new() -> Self {
-        let secret_key = Ed25519SecretKey::new();
+        Self::new_with_rng(&mut thread_rng())
+    }
+
+    /// Create a new random signing key, using the given random number
+    /// generator.
+    pub fn new_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        let mut seed = Zeroizing::new([0u8; 32]);
+        rng.fill_bytes(seed.as_mut_slice());
+
+        let secret_key =
+            Ed25519SecretKey::from_slice(&seed).expect("A 32 byte seed should be a valid key");
+
         Self::new_helper(secret_key)
     }
 
@@ -280,14 +540,87 @@ impl Signing {
         Ok(Self::new_helper(key))
     }
 
+    /// Create a signing key from the raw bytes of an Ed25519 seed.
+    ///
+    /// Returns an error if the seed isn't exactly 32 bytes long.
+    pub fn from_seed(seed: Vec<u8>) -> Result<Self, SigningError> {
+        let seed = Zeroizing::new(seed);
+        let bytes: &[u8; 32] = seed
+            .as_slice()
+            .try_into()
+            .map_err(|_| SigningError::InvalidSeedLength { expected: 32, got: seed.len() })?;
+
+        Ok(Self::new_helper(Ed25519SecretKey::from_slice(bytes)?))
+    }
+
+    /// Deterministically derive a subkey with the given usage from this master
+    /// key.
+    ///
+    /// This is an alternative to generating the subkeys randomly, the derived
+    /// subkeys can be recovered from the master key alone. The derivation uses
+    /// HKDF-SHA256 with an info string that depends on the given `usage`, only
+    /// the self signing and user signing usages are supported.
+    pub fn derive_subkey(&self, usage: KeyUsage) -> Result<Signing, SigningError> {
+        let info: &[u8] = match usage {
+            KeyUsage::SelfSigning => b"MATRIX_CROSS_SIGNING_DERIVED_SELF_SIGNING_KEY",
+            KeyUsage::UserSigning => b"MATRIX_CROSS_SIGNING_DERIVED_USER_SIGNING_KEY",
+            _ => return Err(SigningError::UnsupportedKeyUsage(usage)),
+        };
+
+        let mut seed = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, self.as_bytes())
+            .expand(info, seed.as_mut_slice())
+            .expect("We should be able to expand a 32 byte long seed");
+
+        Ok(Self::new_helper(Ed25519SecretKey::from_slice(&*seed)?))
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         self.inner.as_bytes()
     }
 
+    /// Export the raw 32 byte seed of this key, e.g. to share it as a secret.
+    ///
+    /// The seed is secret, the returned copy gets zeroized when it's dropped,
+    /// callers that copy it out of the wrapper are responsible for wiping
+    /// those copies.
+    pub fn export_seed(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(*self.inner.as_bytes())
+    }
+
     pub fn from_pickle(pickle: PickledSigning) -> Result<Self, SigningError> {
         Ok(Self::new_helper(pickle.0))
     }
 
+    /// Restore a signing key from a pickle, checking that it belongs to the
+    /// expected public key.
+    ///
+    /// The public keys are compared in constant time.
+    pub fn from_pickle_expecting(
+        pickle: PickledSigning,
+        expected: &Ed25519PublicKey,
+    ) -> Result<Self, SigningError> {
+        let signing = Self::from_pickle(pickle)?;
+
+        if bool::from(signing.public_key.as_bytes()[..].ct_eq(&expected.as_bytes()[..])) {
+            Ok(signing)
+        } else {
+            Err(SigningError::PublicKeyMismatch)
+        }
+    }
+
+    /// Restore a signing key from a pickle that was stored together with the
+    /// public cross signing key it belongs to.
+    fn from_cross_signing_pickle(
+        pickle: PickledSigning,
+        public_key: &CrossSigningKey,
+    ) -> Result<Self, SigningError> {
+        match public_key.keys.values().next() {
+            Some(SigningKey::Ed25519(expected)) => Self::from_pickle_expecting(pickle, expected),
+            _ => Err(SigningError::PublicKeyMismatch),
+        }
+    }
+
     pub fn pickle(&self) -> PickledSigning {
         PickledSigning(
             Ed25519SecretKey::from_slice(self.inner.as_bytes())
@@ -299,14 +632,21 @@ impl Signing {
         self.public_key
     }
 
+    /// Get the key id of this signing key, signatures created by this key are
+    /// stored under this id.
+    pub fn key_id(&self) -> OwnedDeviceKeyId {
+        ed25519_key_id(&self.public_key().to_base64())
+            .expect("The base64 encoded form of our own public key should be a valid key id")
+    }
+
     pub fn cross_signing_key(&self, user_id: OwnedUserId, usage: KeyUsage) -> CrossSigningKey {
-        let keys = BTreeMap::from([(
-            DeviceKeyId::from_parts(
-                DeviceKeyAlgorithm::Ed25519,
-                self.public_key().to_base64().as_str().into(),
-            ),
-            self.inner.public_key().into(),
-        )]);
+        debug_assert!(
+            usage_serializes_to_spec_name(&usage),
+            "The key usage {:?} doesn't serialize to its spec name",
+            usage
+        );
+
+        let keys = BTreeMap::from([(self.key_id(), self.inner.public_key().into())]);
 
         CrossSigningKey::new(user_id, vec![usage], keys, BTreeMap::new())
     }
@@ -320,18 +660,686 @@ impl Signing {
         Ok(self.public_key.verify(message.as_bytes(), signature)?)
     }
 
-    pub fn sign_json(&self, mut json: Value) -> Result<Ed25519Signature, SignatureError> {
+    /// Verify a signature over the canonical JSON form of the given JSON
+    /// object, made by this key.
+    ///
+    /// The object is stripped and canonicalized the same way `sign_json` does
+    /// it, the given value itself is left untouched.
+    pub fn verify_json(
+        &self,
+        value: &Value,
+        signature: &Ed25519Signature,
+    ) -> Result<(), SignatureError> {
+        let canonical_json = canonical_json(value.clone())?;
+
+        Ok(self.public_key.verify(canonical_json.as_bytes(), signature)?)
+    }
+
+    /// Sign the canonical JSON form of the given JSON object.
+    ///
+    /// Only the top level `signatures` and `unsigned` fields are removed before
+    /// the object gets canonicalized. Nested fields with those names, as well
+    /// as fields whose names only differ in case, are part of the signed
+    /// content.
+    ///
+    /// With the `strict-sign` feature enabled, the canonical JSON form is
+    /// parsed again and compared to the object, signing fails if they differ.
+    pub fn sign_json(&self, json: Value) -> Result<Ed25519Signature, SignatureError> {
+        self.sign_json_with(json, &RumaCanonicalizer)
+    }
+
+    /// Sign the canonical JSON form of the given JSON object, produced by the
+    /// given canonicalizer.
+    ///
+    /// This behaves like `sign_json` otherwise.
+    pub fn sign_json_with(
+        &self,
+        mut json: Value,
+        canonicalizer: &impl Canonicalizer,
+    ) -> Result<Ed25519Signature, SignatureError> {
         let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
         let _ = json_object.remove("signatures");
         let _ = json_object.remove("unsigned");
 
-        let canonical_json: CanonicalJsonValue =
-            json.try_into().expect("Can't canonicalize the json value");
+        check_integer_range(&json)?;
+
+        #[cfg(feature = "strict-sign")]
+        let stripped = json.clone();
 
-        Ok(self.sign(&canonical_json.to_string()))
+        let canonical_json = canonicalizer.canonicalize(json)?;
+
+        #[cfg(feature = "strict-sign")]
+        if serde_json::from_str::<Value>(&canonical_json)? != stripped {
+            return Err(SignatureError::NonRoundTrippable);
+        }
+
+        Ok(self.sign(&canonical_json))
+    }
+
+    /// Parse the given string as a JSON object and sign its canonical JSON
+    /// form.
+    ///
+    /// Unlike `serde_json`, which keeps the last value if an object contains a
+    /// key multiple times, this rejects such objects since other
+    /// implementations might interpret them differently.
+    pub fn sign_json_str(&self, json: &str) -> Result<Ed25519Signature, SignatureError> {
+        let duplicate_key = Cell::new(false);
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+
+        let json = StrictValue { duplicate_key: &duplicate_key }
+            .deserialize(&mut deserializer)
+            .and_then(|json| deserializer.end().map(|()| json))
+            .map_err(|e| {
+                if duplicate_key.get() {
+                    SignatureError::DuplicateKey
+                } else {
+                    SignatureError::JsonError(e)
+                }
+            })?;
+
+        self.sign_json(json)
     }
 
     pub fn sign(&self, message: &str) -> Ed25519Signature {
         self.inner.sign(message.as_bytes())
     }
 }
+
+/// Check that the given cross signing key usage serializes to the name the
+/// spec uses for it.
+///
+/// The usage is part of the signed content of a cross signing key, a
+/// different serialized form would break the signatures of every other
+/// client. Usages that aren't cross signing key usages pass the check.
+pub fn usage_serializes_to_spec_name(usage: &KeyUsage) -> bool {
+    spec_usage_name(usage)
+        .map_or(true, |name| serde_json::to_value(usage).ok() == Some(Value::from(name)))
+}
+
+/// Get the name the spec uses for the given cross signing key usage.
+///
+/// Returns `None` for usages that aren't cross signing key usages.
+fn spec_usage_name(usage: &KeyUsage) -> Option<&'static str> {
+    match usage {
+        KeyUsage::Master => Some("master"),
+        KeyUsage::SelfSigning => Some("self_signing"),
+        KeyUsage::UserSigning => Some("user_signing"),
+        _ => None,
+    }
+}
+
+/// Deserializes a JSON value and fails if any of its objects contain duplicate
+/// keys.
+///
+/// The given flag gets set if the deserialization failed because of a
+/// duplicate key, to tell such failures apart from other deserialization
+/// errors.
+#[derive(Clone, Copy)]
+struct StrictValue<'a> {
+    duplicate_key: &'a Cell<bool>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for StrictValue<'a> {
+    type Value = Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for StrictValue<'a> {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("a JSON value without duplicate object keys")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Value, E> {
+        Ok(Value::Bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Value, E> {
+        Ok(value.into())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Value, E> {
+        Ok(value.into())
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Value, E> {
+        Ok(Number::from_f64(value).map_or(Value::Null, Value::Number))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Value, E> {
+        Ok(Value::String(value.to_owned()))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<Value, E> {
+        Ok(Value::String(value))
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::new();
+
+        while let Some(value) = seq.next_element_seed(self)? {
+            values.push(value);
+        }
+
+        Ok(Value::Array(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut object = Map::new();
+
+        while let Some(key) = map.next_key::<String>()? {
+            if object.contains_key(&key) {
+                self.duplicate_key.set(true);
+                return Err(de::Error::custom(format!("duplicate key `{}`", key)));
+            }
+
+            let value = map.next_value_seed(self)?;
+            object.insert(key, value);
+        }
+
+        Ok(Value::Object(object))
+    }
+}
+
+/// Remove the `signatures` and `unsigned` fields from the given JSON object and
+/// convert it into its canonical JSON form.
+fn canonical_json(mut json: Value) -> Result<String, SignatureError> {
+    let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+    let _ = json_object.remove("signatures");
+    let _ = json_object.remove("unsigned");
+
+    check_integer_range(&json)?;
+
+    RumaCanonicalizer.canonicalize(json)
+}
+
+/// Check that signing works on this platform.
+///
+/// A new key signs a known object, gets pickled and unpickled, and the
+/// signature is verified using the unpickled key.
+pub fn signing_self_test() -> Result<(), SelfTestError> {
+    signing_self_test_with(&RumaCanonicalizer)
+}
+
+/// Run the signing self test, canonicalizing the known object with the given
+/// canonicalizer when signing it.
+///
+/// The signature is always verified against the canonical form
+/// `RumaCanonicalizer` produces.
+pub fn signing_self_test_with(canonicalizer: &impl Canonicalizer) -> Result<(), SelfTestError> {
+    let signing = Signing::new();
+    let object = json!({
+        "user_id": "@self-test:localhost",
+        "usage": ["master"],
+        "keys": { "ed25519:SELFTEST": "key" },
+    });
+
+    let signature =
+        signing.sign_json_with(object.clone(), canonicalizer).map_err(SelfTestError::Sign)?;
+
+    let pickle = serde_json::to_string(&signing.pickle()).map_err(SigningError::from)?;
+    let restored =
+        Signing::from_pickle(serde_json::from_str(&pickle).map_err(SigningError::from)?)?;
+
+    if restored != signing {
+        return Err(SelfTestError::PublicKeyMismatch);
+    }
+
+    restored.verify_json(&object, &signature).map_err(SelfTestError::Verify)
+}
+
+/// Record the result of the given signature verification in the verification
+/// metrics.
+fn record_verification<T>(
+    verify: impl FnOnce() -> Result<T, SignatureError>,
+) -> Result<T, SignatureError> {
+    let result = verify();
+
+    #[cfg(feature = "metrics")]
+    crate::olm::utility::metrics::record(&result);
+
+    result
+}
+
+/// Verify a signature over the given JSON object using any of the given
+/// candidate public keys.
+///
+/// This is useful if the key that created the signature was rotated and the
+/// signature might have been created by the current or a previous key.
+///
+/// Returns the index of the first candidate that successfully verified the
+/// signature.
+pub fn verify_against_any(
+    content: &Value,
+    signature: &Ed25519Signature,
+    candidates: &[Ed25519PublicKey],
+) -> Result<usize, SignatureError> {
+    record_verification(|| {
+        let canonical_json = canonical_json(content.clone())?;
+        let mut error = SignatureError::MissingSigningKey;
+
+        for (index, candidate) in candidates.iter().enumerate() {
+            match candidate.verify(canonical_json.as_bytes(), signature) {
+                Ok(()) => return Ok(index),
+                Err(e) => error = e.into(),
+            }
+        }
+
+        Err(error)
+    })
+}
+
+/// The key type of Ed25519 keys in the SSH wire format.
+const SSH_ED25519_KEY_TYPE: &str = "ssh-ed25519";
+
+/// Encode the public key as an SSH public key blob, the key type followed by
+/// the key, each prefixed with its length as a big endian `u32`.
+fn ssh_public_key_blob(public_key: &Ed25519PublicKey) -> Vec<u8> {
+    let mut blob = Vec::new();
+
+    for field in [SSH_ED25519_KEY_TYPE.as_bytes(), public_key.as_bytes().as_slice()] {
+        blob.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        blob.extend_from_slice(field);
+    }
+
+    blob
+}
+
+/// Read a single length prefixed field of an SSH public key blob, advancing
+/// the blob past it.
+fn read_ssh_field<'a>(blob: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if blob.len() < 4 {
+        return None;
+    }
+
+    let (length, rest) = blob.split_at(4);
+    let length = u32::from_be_bytes(length.try_into().ok()?) as usize;
+
+    if rest.len() < length {
+        return None;
+    }
+
+    let (field, rest) = rest.split_at(length);
+    *blob = rest;
+
+    Some(field)
+}
+
+/// Parse a line of an SSH `authorized_keys` file containing an `ssh-ed25519`
+/// key.
+///
+/// Returns the public key and the, possibly empty, comment of the line.
+pub fn parse_authorized_key_line(line: &str) -> Result<(Ed25519PublicKey, String), SigningError> {
+    let mut parts = line.trim().splitn(3, char::is_whitespace);
+
+    if parts.next() != Some(SSH_ED25519_KEY_TYPE) {
+        return Err(SigningError::InvalidAuthorizedKey("the key type isn't ssh-ed25519"));
+    }
+
+    let blob = parts.next().ok_or(SigningError::InvalidAuthorizedKey("the key is missing"))?;
+    let blob = decode_config(blob, STANDARD)?;
+    let comment = parts.next().unwrap_or_default().trim().to_owned();
+
+    let mut rest = blob.as_slice();
+    let key_type = read_ssh_field(&mut rest);
+    let key = read_ssh_field(&mut rest);
+
+    match (key_type, key) {
+        (Some(key_type), Some(key))
+            if key_type == SSH_ED25519_KEY_TYPE.as_bytes() && rest.is_empty() =>
+        {
+            let key: &[u8; 32] = key
+                .try_into()
+                .map_err(|_| SigningError::InvalidAuthorizedKey("the key has an invalid length"))?;
+
+            Ok((Ed25519PublicKey::from_slice(key)?, comment))
+        }
+        _ => Err(SigningError::InvalidAuthorizedKey("the key blob is malformed")),
+    }
+}
+
+/// The length of an unpadded base64 encoded Ed25519 public key.
+const ED25519_PUBLIC_KEY_BASE64_LENGTH: usize = 43;
+
+/// Create the key id for an externally provided, base64 encoded, Ed25519
+/// public key.
+///
+/// Returns an error if the encoded key doesn't have the length an unpadded
+/// base64 encoded Ed25519 key has or if it doesn't decode to a valid Ed25519
+/// key. The length is checked first, so oversized input is rejected before
+/// decoding it.
+pub fn ed25519_key_id(public_key: &str) -> Result<OwnedDeviceKeyId, SignatureError> {
+    if public_key.len() != ED25519_PUBLIC_KEY_BASE64_LENGTH {
+        return Err(SignatureError::InvalidPublicKeyLength {
+            expected: ED25519_PUBLIC_KEY_BASE64_LENGTH,
+            got: public_key.len(),
+        });
+    }
+
+    Ed25519PublicKey::from_base64(public_key)?;
+
+    Ok(DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, public_key.into()))
+}
+
+/// Verify a signature over the given JSON object and return the canonical JSON
+/// form it was checked against.
+///
+/// If the signature doesn't verify, the canonical JSON form is part of the
+/// returned error, so it can be compared to the one the signer produced.
+pub fn verify_json_verbose(
+    public_key: &Ed25519PublicKey,
+    json: &Value,
+    signature: &Ed25519Signature,
+) -> Result<Arc<str>, SignatureError> {
+    record_verification(|| {
+        let canonical_json: Arc<str> = canonical_json(json.clone())?.into();
+
+        match public_key.verify(canonical_json.as_bytes(), signature) {
+            Ok(()) => Ok(canonical_json),
+            Err(source) => {
+                Err(SignatureError::CanonicalJsonVerificationError { canonical_json, source })
+            }
+        }
+    })
+}
+
+/// Verify a signature over the given JSON object where the key id, the public
+/// key and the signature are all given as strings.
+///
+/// The key id needs to be a valid Ed25519 key id, the public key and the
+/// signature need to be unpadded base64. The `signatures` and `unsigned`
+/// fields of the content aren't part of the signed content.
+pub fn verify_raw(
+    key_id: &str,
+    public_key_b64: &str,
+    content: &Value,
+    signature_b64: &str,
+) -> Result<(), SignatureError> {
+    record_verification(|| {
+        let key_id = <&DeviceKeyId>::try_from(key_id)?;
+
+        if key_id.algorithm() != DeviceKeyAlgorithm::Ed25519 {
+            return Err(SignatureError::UnsupportedAlgorithm);
+        }
+
+        let public_key = Ed25519PublicKey::from_base64(public_key_b64)?;
+        let signature = decode_signature(signature_b64)?;
+        let canonical_json = canonical_json(content.clone())?;
+
+        Ok(public_key.verify(canonical_json.as_bytes(), &signature)?)
+    })
+}
+
+/// Verify an Ed25519 signature over the given message where the public key and
+/// the signature are given as raw bytes.
+///
+/// This doesn't tie callers to a specific Ed25519 implementation, the bytes
+/// of a key or signature from any of them can be passed in.
+pub fn verify_with_bytes(
+    public_key: &[u8; 32],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), SignatureError> {
+    record_verification(|| {
+        let public_key = Ed25519PublicKey::from_slice(public_key)?;
+        let signature = Ed25519Signature::from_slice(signature)?;
+
+        Ok(public_key.verify(message, &signature)?)
+    })
+}
+
+/// Verify a signature over the given message using a public key that doesn't
+/// need to belong to us.
+///
+/// Both the public key and the signature need to be unpadded base64.
+pub fn verify_detached(
+    public_key: &str,
+    message: &str,
+    signature: &str,
+) -> Result<(), SignatureError> {
+    record_verification(|| {
+        let public_key = Ed25519PublicKey::from_base64(public_key)?;
+        let signature = decode_signature(signature)?;
+
+        Ok(public_key.verify(message.as_bytes(), &signature)?)
+    })
+}
+
+/// Verify a signed `m.key.verification` message and extract its transaction
+/// id.
+///
+/// The signature of the given user and key id is checked with the given
+/// public key.
+pub fn verify_verification_message(
+    public_key: &Ed25519PublicKey,
+    user_id: &UserId,
+    key_id: &DeviceKeyId,
+    message: &Value,
+) -> Result<VerifiedMessage, SignatureError> {
+    use crate::olm::VerifyJson;
+
+    public_key.verify_json(user_id, key_id, &mut message.clone())?;
+
+    let transaction_id = message
+        .get("transaction_id")
+        .and_then(Value::as_str)
+        .ok_or(SignatureError::MissingTransactionId)?;
+
+    Ok(VerifiedMessage { transaction_id: transaction_id.to_owned() })
+}
+
+/// Verify a signed JSON object that was canonicalized with the given
+/// canonicalizer before it was signed.
+///
+/// The signature of the given user and key id is checked with the given
+/// public key, the given JSON object is left untouched.
+pub fn verify_json_with_canonicalizer(
+    public_key: &Ed25519PublicKey,
+    user_id: &UserId,
+    key_id: &DeviceKeyId,
+    json: &Value,
+    canonicalizer: &impl Canonicalizer,
+) -> Result<(), SignatureError> {
+    use crate::olm::VerifyJson;
+
+    public_key.verify_json_with(user_id, key_id, &mut json.clone(), canonicalizer)
+}
+
+/// Verify a signed JSON object that might have been signed by an old client.
+///
+/// Contrary to the spec, some old clients didn't strip the `unsigned` field
+/// before signing. This first tries to verify the signature the spec compliant
+/// way and, if that fails, retries with the `unsigned` field being part of the
+/// signed content.
+#[cfg(feature = "legacy-compat")]
+pub fn verify_json_legacy_unsigned(
+    public_key: &Ed25519PublicKey,
+    user_id: &UserId,
+    key_id: &DeviceKeyId,
+    json: &Value,
+) -> Result<(), SignatureError> {
+    use crate::olm::utility::verify_json_helper;
+
+    record_verification(|| {
+        let error = match verify_json_helper(
+            public_key,
+            user_id,
+            key_id,
+            &mut json.clone(),
+            &RumaCanonicalizer,
+        ) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let mut json = json.clone();
+        let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+        let signatures =
+            json_object.remove("signatures").ok_or(SignatureError::NoSignatureFound)?;
+
+        let signature = signatures
+            .get(user_id.as_str())
+            .and_then(|s| s.get(key_id.as_str()))
+            .and_then(|s| s.as_str())
+            .ok_or(SignatureError::NoSignatureFound)?;
+        let signature = decode_signature(signature)?;
+
+        let canonical_json = RumaCanonicalizer.canonicalize(json)?;
+
+        public_key.verify(canonical_json.as_bytes(), &signature).map_err(|_| error)
+    })
+}
+
+/// Check if all the signatures the owner of the given cross signing key put on
+/// it were made by devices that don't exist anymore.
+///
+/// Signatures made by the key itself or by one of the given cross signing
+/// keys of the owner, e.g. the master key signature on a subkey, aren't device
+/// signatures and are skipped. Every other signature made by the key owner
+/// whose key id doesn't name one of the given active devices counts as a
+/// signature of a deleted device. Returns `false` if no device of the owner
+/// signed the key at all.
+pub fn signers_are_all_deleted(
+    content: &CrossSigningKey,
+    cross_signing_keys: &[Ed25519PublicKey],
+    active_device_ids: &BTreeSet<OwnedDeviceId>,
+) -> bool {
+    let is_cross_signing_key = |key_id: &OwnedDeviceKeyId| {
+        let key_name = key_id.key_name().as_str();
+
+        content.keys.values().any(|key| match key {
+            SigningKey::Ed25519(key) => key.to_base64() == key_name,
+            _ => false,
+        }) || cross_signing_keys.iter().any(|key| key.to_base64() == key_name)
+    };
+
+    content.signatures.get(&content.user_id).map_or(false, |signatures| {
+        let mut device_signers =
+            signatures.keys().filter(|key_id| !is_cross_signing_key(key_id)).peekable();
+
+        device_signers.peek().is_some()
+            && device_signers.all(|key_id| !active_device_ids.contains(key_id.key_name()))
+    })
+}
+
+/// List the user and key id pairs of all the signatures the given cross signing
+/// key carries.
+pub fn signature_key_ids(content: &CrossSigningKey) -> Vec<(OwnedUserId, OwnedDeviceKeyId)> {
+    content
+        .signatures
+        .iter()
+        .flat_map(|(user_id, signatures)| {
+            signatures.keys().map(move |key_id| (user_id.clone(), key_id.clone()))
+        })
+        .collect()
+}
+
+/// Check that the signatures the given cross signing key claims to carry from
+/// itself are valid.
+///
+/// A signature filed under the owner of the key and under the id of the key
+/// itself needs to verify against the embedded public key. Keys that aren't
+/// signed by themselves pass the check.
+pub fn verify_self_signatures(content: &CrossSigningKey) -> Result<(), SignatureError> {
+    use crate::olm::VerifyJson;
+
+    let signatures = match content.signatures.get(&content.user_id) {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+
+    for (key_id, key) in &content.keys {
+        if !signatures.contains_key(key_id) {
+            continue;
+        }
+
+        if let SigningKey::Ed25519(key) = key {
+            key.verify_json(&content.user_id, key_id, &mut serde_json::to_value(content)?)?;
+        } else {
+            return Err(SignatureError::UnsupportedAlgorithm);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove all the signatures from the given cross signing key that weren't
+/// made by one of the given users.
+///
+/// The signatures of the kept users are left untouched, so they stay valid.
+pub fn prune_signatures(content: &mut CrossSigningKey, keep_users: &BTreeSet<OwnedUserId>) {
+    content.signatures.retain(|user_id, _| keep_users.contains(user_id));
+}
+
+/// Move the signatures of the given cross signing key that are filed under the
+/// `old` key id to the `new` key id.
+///
+/// If the key name of the new key id is an Ed25519 public key, the moved
+/// signatures need to verify under that key, otherwise the content is left
+/// untouched and an error is returned.
+pub fn remap_signature_key_id(
+    content: &mut CrossSigningKey,
+    old: &DeviceKeyId,
+    new: &DeviceKeyId,
+) -> Result<(), SignatureError> {
+    if let Ok(new_key) = Ed25519PublicKey::from_base64(new.key_name().as_str()) {
+        let canonical_json = canonical_json(serde_json::to_value(&*content)?)?;
+
+        for signature in content.signatures.values().filter_map(|s| s.get(old)) {
+            let signature = decode_signature(signature)?;
+            new_key.verify(canonical_json.as_bytes(), &signature)?;
+        }
+    }
+
+    for signatures in content.signatures.values_mut() {
+        if let Some(signature) = signatures.remove(old) {
+            signatures.insert(new.to_owned(), signature);
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the signatures of our local copy of a cross signing key that the remote
+/// copy is missing.
+///
+/// A signature counts as missing if the remote copy doesn't have a signature
+/// for the same user and key id or if it has a different one.
+pub fn signatures_to_upload(
+    local: &CrossSigningKey,
+    remote: &CrossSigningKey,
+) -> CrossSigningKeySignatures {
+    let mut missing = CrossSigningKeySignatures::new();
+
+    for (user_id, signatures) in &local.signatures {
+        let remote_signatures = remote.signatures.get(user_id);
+
+        for (key_id, signature) in signatures {
+            if remote_signatures.and_then(|s| s.get(key_id)) != Some(signature) {
+                missing
+                    .entry(user_id.to_owned())
+                    .or_default()
+                    .insert(key_id.to_owned(), signature.to_owned());
+            }
+        }
+    }
+
+    missing
+}
@@ -16,8 +16,76 @@ use std::convert::TryInto;
 
 use ruma::{serde::CanonicalJsonValue, DeviceKeyAlgorithm, DeviceKeyId, UserId};
 use serde_json::Value;
+use vodozemac::Ed25519Signature;
 
-use crate::error::SignatureError;
+use crate::{error::SignatureError, utilities::decode};
+
+/// The largest integer canonical JSON can represent, `2^53 - 1`.
+const MAX_CANONICAL_INTEGER: i64 = (1 << 53) - 1;
+
+/// The length of a decoded Ed25519 signature.
+const ED25519_SIGNATURE_LENGTH: usize = 64;
+
+/// Decode the given base64 encoded Ed25519 signature.
+///
+/// Returns an `InvalidSignatureLength` error if the signature doesn't decode to
+/// exactly 64 bytes.
+pub(crate) fn decode_signature(signature: &str) -> Result<Ed25519Signature, SignatureError> {
+    match decode(signature) {
+        Ok(bytes) if bytes.len() != ED25519_SIGNATURE_LENGTH => {
+            Err(SignatureError::InvalidSignatureLength { got: bytes.len() })
+        }
+        // Leave the reporting of invalid base64 to vodozemac.
+        _ => Ok(Ed25519Signature::from_base64(signature)?),
+    }
+}
+
+/// Convert the given JSON value into its canonical JSON string form.
+pub(crate) fn to_canonical_string(value: Value) -> Result<String, SignatureError> {
+    let canonical_json: CanonicalJsonValue = value.try_into()?;
+
+    Ok(canonical_json.to_string())
+}
+
+/// Check that all the integers in the given JSON value are within the
+/// `[-(2^53 - 1), 2^53 - 1]` range canonical JSON supports.
+pub(crate) fn check_integer_range(value: &Value) -> Result<(), SignatureError> {
+    match value {
+        Value::Number(number) => {
+            let in_range = match number.as_i64() {
+                Some(n) => (-MAX_CANONICAL_INTEGER..=MAX_CANONICAL_INTEGER).contains(&n),
+                // Floats aren't integers, everything else is a u64 larger than
+                // i64::MAX.
+                None => number.is_f64(),
+            };
+
+            if in_range {
+                Ok(())
+            } else {
+                Err(SignatureError::IntegerOutOfRange)
+            }
+        }
+        Value::Array(values) => values.iter().try_for_each(check_integer_range),
+        Value::Object(object) => object.values().try_for_each(check_integer_range),
+        _ => Ok(()),
+    }
+}
+
+/// Turns a JSON object into the canonical string form that gets signed.
+pub trait Canonicalizer {
+    /// Get the canonical form of the given JSON object.
+    fn canonicalize(&self, json: Value) -> Result<String, SignatureError>;
+}
+
+/// The default canonicalizer, based on ruma's `CanonicalJsonValue`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RumaCanonicalizer;
+
+impl Canonicalizer for RumaCanonicalizer {
+    fn canonicalize(&self, json: Value) -> Result<String, SignatureError> {
+        to_canonical_string(json)
+    }
+}
 
 pub trait VerifyJson {
     /// Verify a signed JSON object.
@@ -32,7 +100,8 @@ pub trait VerifyJson {
     ///
     /// * `user_id` - The user who signed the JSON object.
     ///
-    /// * `key_id` - The id of the key that signed the JSON object.
+    /// * `key_id` - The id of the key that signed the JSON object. The id is
+    /// matched as a whole, including any version suffix it might carry.
     ///
     /// * `json` - The JSON object that should be verified.
     fn verify_json(
@@ -40,55 +109,140 @@ pub trait VerifyJson {
         user_id: &UserId,
         key_id: &DeviceKeyId,
         json: &mut Value,
+    ) -> Result<(), SignatureError> {
+        self.verify_json_with(user_id, key_id, json, &RumaCanonicalizer)
+    }
+
+    /// Verify a signed JSON object, canonicalizing it with the given
+    /// canonicalizer.
+    ///
+    /// This needs to be the canonicalizer the object was signed with, see
+    /// `verify_json` for the rest of the arguments.
+    fn verify_json_with(
+        &self,
+        user_id: &UserId,
+        key_id: &DeviceKeyId,
+        json: &mut Value,
+        canonicalizer: &impl Canonicalizer,
     ) -> Result<(), SignatureError>;
 }
 
 impl VerifyJson for vodozemac::Ed25519PublicKey {
-    fn verify_json(
+    fn verify_json_with(
         &self,
         user_id: &UserId,
         key_id: &DeviceKeyId,
         json: &mut Value,
+        canonicalizer: &impl Canonicalizer,
     ) -> Result<(), SignatureError> {
-        if key_id.algorithm() != DeviceKeyAlgorithm::Ed25519 {
-            return Err(SignatureError::UnsupportedAlgorithm);
-        }
+        let result = verify_json_helper(self, user_id, key_id, json, canonicalizer);
+
+        #[cfg(feature = "metrics")]
+        metrics::record(&result);
+
+        result
+    }
+}
+
+/// Verify a signed JSON object without recording the result in the
+/// verification metrics.
+pub(crate) fn verify_json_helper(
+    key: &vodozemac::Ed25519PublicKey,
+    user_id: &UserId,
+    key_id: &DeviceKeyId,
+    json: &mut Value,
+    canonicalizer: &impl Canonicalizer,
+) -> Result<(), SignatureError> {
+    if key_id.algorithm() != DeviceKeyAlgorithm::Ed25519 {
+        return Err(SignatureError::UnsupportedAlgorithm);
+    }
+
+    let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+    let unsigned = json_object.remove("unsigned");
+    let signatures = json_object.remove("signatures");
+
+    check_integer_range(json)?;
+
+    let canonical_json = canonicalizer.canonicalize(json.clone())?;
 
-        let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
-        let unsigned = json_object.remove("unsigned");
-        let signatures = json_object.remove("signatures");
+    let signatures = signatures.ok_or(SignatureError::NoSignatureFound)?;
+    let signature_object = signatures.as_object().ok_or(SignatureError::NoSignatureFound)?;
+    let signature =
+        signature_object.get(user_id.as_str()).ok_or(SignatureError::NoSignatureFound)?;
+    let signature = signature.get(key_id.to_string()).ok_or(SignatureError::NoSignatureFound)?;
+    let signature = signature.as_str().ok_or(SignatureError::NoSignatureFound)?;
+
+    let signature = decode_signature(signature)?;
+
+    let ret = key
+        .verify(canonical_json.as_bytes(), &signature)
+        .map_err(SignatureError::VerificationError);
+
+    let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+
+    if let Some(u) = unsigned {
+        json_object.insert("unsigned".to_owned(), u);
+    }
 
-        let canonical_json: CanonicalJsonValue =
-            json.clone().try_into().map_err(|_| SignatureError::NotAnObject)?;
+    json_object.insert("signatures".to_owned(), signatures);
 
-        let canonical_json: String = canonical_json.to_string();
+    ret
+}
 
-        let signatures = signatures.ok_or(SignatureError::NoSignatureFound)?;
-        let signature_object = signatures.as_object().ok_or(SignatureError::NoSignatureFound)?;
-        let signature =
-            signature_object.get(user_id.as_str()).ok_or(SignatureError::NoSignatureFound)?;
-        let signature =
-            signature.get(key_id.to_string()).ok_or(SignatureError::NoSignatureFound)?;
-        let signature = signature.as_str().ok_or(SignatureError::NoSignatureFound)?;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics {
+    use std::sync::atomic::{AtomicU64, Ordering};
 
-        let signature = vodozemac::Ed25519Signature::from_base64(signature)?;
+    use crate::error::SignatureError;
 
-        let ret = self
-            .verify(canonical_json.as_bytes(), &signature)
-            .map_err(SignatureError::VerificationError);
+    static VALID: AtomicU64 = AtomicU64::new(0);
+    static INVALID: AtomicU64 = AtomicU64::new(0);
+    static SKIPPED: AtomicU64 = AtomicU64::new(0);
+    static MISSING: AtomicU64 = AtomicU64::new(0);
 
-        let json_object = json.as_object_mut().ok_or(SignatureError::NotAnObject)?;
+    /// Aggregate counts of the signature verifications done since the process
+    /// started.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct VerificationStats {
+        /// The number of signatures that were successfully verified.
+        pub valid: u64,
+        /// The number of signatures that failed to verify.
+        pub invalid: u64,
+        /// The number of signatures that weren't checked because they use an
+        /// unsupported algorithm.
+        pub skipped: u64,
+        /// The number of signatures that weren't checked because the signed
+        /// object didn't contain them.
+        pub missing: u64,
+    }
 
-        if let Some(u) = unsigned {
-            json_object.insert("unsigned".to_owned(), u);
+    impl VerificationStats {
+        /// Take a snapshot of the current verification counters.
+        pub fn snapshot() -> Self {
+            Self {
+                valid: VALID.load(Ordering::Relaxed),
+                invalid: INVALID.load(Ordering::Relaxed),
+                skipped: SKIPPED.load(Ordering::Relaxed),
+                missing: MISSING.load(Ordering::Relaxed),
+            }
         }
+    }
 
-        json_object.insert("signatures".to_owned(), signatures);
+    pub(crate) fn record<T>(result: &Result<T, SignatureError>) {
+        let counter = match result {
+            Ok(_) => &VALID,
+            Err(SignatureError::UnsupportedAlgorithm) => &SKIPPED,
+            Err(SignatureError::NoSignatureFound) => &MISSING,
+            Err(_) => &INVALID,
+        };
 
-        ret
+        counter.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+#[cfg(feature = "metrics")]
+pub use metrics::VerificationStats;
+
 #[cfg(test)]
 mod tests {
     use ruma::{device_id, user_id, DeviceKeyAlgorithm, DeviceKeyId};
@@ -96,6 +250,7 @@ mod tests {
     use vodozemac::Ed25519PublicKey;
 
     use super::VerifyJson;
+    use crate::error::SignatureError;
 
     #[test]
     fn signature_test() {
@@ -133,4 +288,76 @@ mod tests {
             )
             .expect("Can't verify device keys");
     }
+
+    #[test]
+    fn out_of_range_integers_are_rejected() {
+        let mut json = json!({
+            "counter": 9007199254740993u64,
+            "signatures": {
+                "@example:localhost": {
+                    "ed25519:GBEWHQOYGS": "OlF2REsqjYdAfr04ONx8VS/5cB7KjrWYRlLF4eUm2foAiQL/RAfsjsa2JXZeoOHh6vEualZHbWlod49OewVqBg"
+                }
+            },
+        });
+
+        let signing_key =
+            Ed25519PublicKey::from_base64("n469gw7zm+KW+JsFIJKnFVvCKU14HwQyocggcCIQgZY").unwrap();
+
+        let result = signing_key.verify_json(
+            user_id!("@example:localhost"),
+            &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("GBEWHQOYGS")),
+            &mut json,
+        );
+
+        assert!(matches!(result, Err(SignatureError::IntegerOutOfRange)));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn verification_stats() {
+        use super::VerificationStats;
+
+        let signing_key =
+            Ed25519PublicKey::from_base64("n469gw7zm+KW+JsFIJKnFVvCKU14HwQyocggcCIQgZY").unwrap();
+        let mut json = json!({
+            "user_id": "@example:localhost",
+            "signatures": {
+                "@example:localhost": {
+                    "ed25519:GBEWHQOYGS": "OlF2REsqjYdAfr04ONx8VS/5cB7KjrWYRlLF4eUm2foAiQL/RAfsjsa2JXZeoOHh6vEualZHbWlod49OewVqBg"
+                }
+            },
+        });
+
+        let before = VerificationStats::snapshot();
+
+        signing_key
+            .verify_json(
+                user_id!("@example:localhost"),
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("GBEWHQOYGS")),
+                &mut json,
+            )
+            .unwrap_err();
+        signing_key
+            .verify_json(
+                user_id!("@example:localhost"),
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Curve25519, device_id!("GBEWHQOYGS")),
+                &mut json,
+            )
+            .unwrap_err();
+        signing_key
+            .verify_json(
+                user_id!("@example:localhost"),
+                &DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device_id!("GBEWHQOYGS")),
+                &mut json!({ "user_id": "@example:localhost" }),
+            )
+            .unwrap_err();
+
+        let after = VerificationStats::snapshot();
+
+        // Other tests might verify signatures concurrently, so the counters
+        // can only be checked for a lower bound.
+        assert!(after.invalid > before.invalid);
+        assert!(after.skipped > before.skipped);
+        assert!(after.missing > before.missing);
+    }
 }
@@ -14,6 +14,7 @@
 
 pub use base64::DecodeError;
 use base64::{decode_config, encode_config, STANDARD_NO_PAD};
+use vodozemac::Ed25519PublicKey;
 
 /// Decode the input as base64 with no padding.
 pub fn decode(input: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
@@ -24,3 +25,14 @@ pub fn decode(input: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
 pub fn encode(input: impl AsRef<[u8]>) -> String {
     encode_config(input, STANDARD_NO_PAD)
 }
+
+/// Format the given public key as uppercase hex, grouped into blocks of four
+/// characters separated by a space.
+pub fn fingerprint(public_key: &Ed25519PublicKey) -> String {
+    public_key
+        .as_bytes()
+        .chunks(2)
+        .map(|chunk| chunk.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}